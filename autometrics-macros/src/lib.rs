@@ -1,14 +1,16 @@
-use crate::parse::{Args, Item};
+use crate::parse::{Args, Buckets, Item};
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use proc_macro2::TokenStream;
-use quote::quote;
+use proc_macro2::{TokenStream, TokenTree};
+use quote::{quote, ToTokens};
 use std::env;
-use syn::{parse_macro_input, ImplItem, ItemFn, ItemImpl, Result};
+use syn::{
+    parse_macro_input, ImplItem, ItemFn, ItemImpl, Path, Result, ReturnType, Signature, Type,
+};
 
 mod parse;
 
 const COUNTER_NAME_PROMETHEUS: &str = "function_calls_count";
-const HISTOGRAM_BUCKET_NAME_PROMETHEUS: &str = "function_calls_duration_bucket";
+const HISTOGRAM_NAME_PROMETHEUS: &str = "function_calls_duration";
 const GAUGE_NAME_PROMETHEUS: &str = "function_calls_concurrent";
 
 const DEFAULT_PROMETHEUS_URL: &str = "http://localhost:9090";
@@ -27,8 +29,13 @@ const DEFAULT_PROMETHEUS_URL: &str = "http://localhost:9090";
 /// - `module` - the module path of the function (with `::` replaced by `.`)
 ///
 /// For the function call counter, Autometrics attaches these additional labels:
-/// - `result` - if the function returns a `Result`, this will either be `ok` or `error`
+/// - `result` - if the function returns a `Result`, this will either be `ok` or `error`.
+///   If the function panics, the call is recorded with `result` set to `panic`.
+///   If the future returned by an `async` function is dropped before it completes, the call is recorded
+///   with `result` set to `cancelled`
 /// - `caller` - the name of the (autometrics-instrumented) function that called the current function
+/// - (optional) `caller_service` - the service of the remote caller, if the function was called
+///   within `autometrics::with_remote_caller` and the caller's service is known
 /// - (optional) `ok`/`error` - if the inner type implements `Into<&'static str>`, that value will be used as this label's value
 ///
 /// ## Optional Parameters
@@ -66,6 +73,73 @@ const DEFAULT_PROMETHEUS_URL: &str = "http://localhost:9090";
 /// This may be most useful for top-level functions such as the main HTTP handler that
 /// passes requests off to other functions.
 ///
+/// ### `labels`
+///
+/// Example:
+/// ```rust
+/// use autometrics::autometrics;
+///
+/// #[autometrics(labels(team = "payments", tier = "critical"))]
+/// pub fn charge_card(amount: u64) -> Result<(), String> {
+///     // ...
+/// #   let _ = amount;
+/// #   Ok(())
+/// }
+/// ```
+///
+/// Attach additional labels with fixed values to all of the metrics generated for the function.
+/// This can be used to group functions by owner or importance in dashboards and alert routing.
+/// The argument can be repeated, and the labels of all of them are attached.
+///
+/// The label keys `function`, `module`, `caller`, `caller_service`, `result`, `ok`, and `error` are reserved by autometrics.
+///
+/// ### `label`
+///
+/// Example:
+/// ```rust
+/// use autometrics::autometrics;
+/// # pub struct MyRequest { tenant_id: String }
+/// # impl MyRequest {
+/// #     fn tenant_id(&self) -> &str { &self.tenant_id }
+/// # }
+///
+/// #[autometrics(label(tenant = req.tenant_id()))]
+/// pub async fn my_handler(req: MyRequest) -> Result<String, String> {
+///     // ...
+/// #   Ok(req.tenant_id)
+/// }
+/// ```
+///
+/// Attach a label whose value is computed from the function's arguments each time the function is called.
+/// The expression is evaluated before the function body runs and must return something that
+/// implements `AsRef<str>`, such as a `&str` or `String`.
+/// Like `labels`, the argument can be repeated, and `dynamic_labels` is accepted as another name for it.
+///
+/// To keep the number of time series bounded, each distinct value is interned and only the
+/// first 1000 values for a given label key are recorded as-is.
+/// Any values beyond that are recorded as `__autometrics_other`.
+/// Use this for labels like tenant or region IDs that have a limited number of possible values,
+/// **not** for things like user or request IDs.
+///
+/// ### `buckets`
+///
+/// Example:
+/// ```rust
+/// #[autometrics(buckets = "micro")]
+/// #[autometrics(buckets = [0.0001, 0.001, 0.01, 0.1])]
+/// ```
+///
+/// Configure the buckets (in seconds) of the histogram used to track the function's latency.
+/// By default, the buckets range from 10 milliseconds to 1 second, which suits most HTTP handlers.
+/// Pass either a list of bucket boundaries or the name of one of these presets:
+/// - `default` - 10 milliseconds to 1 second
+/// - `micro` - 10 microseconds to 10 milliseconds, for functions like cache lookups
+/// - `batch` - 1 second to 1 hour, for functions like batch jobs
+///
+/// The `prometheus` crate uses the buckets for this function only. The `opentelemetry` and `metrics`
+/// crates only support configuring buckets per metric, so the `prometheus-exporter` uses the
+/// default buckets combined with the buckets configured for every function.
+///
 /// ### `alerts`
 ///
 /// **Only available when the `alerts` feature is enabled.**
@@ -85,7 +159,8 @@ const DEFAULT_PROMETHEUS_URL: &str = "http://localhost:9090";
 /// ⚠️ **Note about `latency` alerts**: The latency target **MUST** match one of the buckets
 /// configured for your histogram. For example, if you want to enforce that a certain percentage of calls
 /// are handled within 200ms, you must have a histogram bucket for 0.2 seconds. If there is no
-/// such bucket, the alert will never fire.
+/// such bucket, the alert will never fire. With the `native-histograms` feature, the latency target
+/// can be any value, because the alerts estimate the fraction of slow calls using `histogram_fraction`.
 ///
 /// ## Instrumenting `impl` blocks
 ///
@@ -109,6 +184,10 @@ const DEFAULT_PROMETHEUS_URL: &str = "http://localhost:9090";
 ///
 /// This will instrument all functions in the `impl` block, except for those that have the `skip_autometrics` attribute.
 ///
+/// The `function` label of methods instrumented this way includes the type the block is implemented for,
+/// as in `MyStruct::my_function`. For trait implementations, the trait name is included as well,
+/// as in `<MyStruct as MyTrait>::my_function`. Generic arguments and module paths are not included.
+///
 #[proc_macro_attribute]
 pub fn autometrics(
     args: proc_macro::TokenStream,
@@ -118,7 +197,7 @@ pub fn autometrics(
     let item = parse_macro_input!(item as Item);
 
     let result = match item {
-        Item::Function(item) => instrument_function(&args, item),
+        Item::Function(item) => instrument_function(&args, item, None),
         Item::Impl(item) => instrument_impl_block(&args, item),
     };
 
@@ -130,14 +209,58 @@ pub fn autometrics(
     output.into()
 }
 
-/// Add autometrics instrumentation to a single function
-fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
+/// Add autometrics instrumentation to a single function.
+///
+/// If the function is a method in an impl block, `impl_name` is used to qualify the function name.
+fn instrument_function(args: &Args, item: ItemFn, impl_name: Option<&str>) -> Result<TokenStream> {
     let track_concurrency = args.track_concurrency;
     let sig = item.sig;
     let block = item.block;
     let vis = item.vis;
     let attrs = item.attrs;
-    let function_name = sig.ident.to_string();
+    let function_name = match impl_name {
+        Some(impl_name) => format!("{impl_name}::{}", sig.ident),
+        None => sig.ident.to_string(),
+    };
+
+    // The static labels are followed by the dynamic labels, which are evaluated against the
+    // function's arguments before the function body (which may move the arguments) is called
+    let label_count = args.labels.len() + args.dynamic_labels.len();
+    let static_labels = args.labels.iter().map(|label| {
+        let key = label.key.to_string();
+        let value = &label.value;
+        quote! { (#key, #value) }
+    });
+    let dynamic_labels = args.dynamic_labels.iter().map(|label| {
+        let key = label.key.to_string();
+        let value = &label.value;
+        quote! { (#key, autometrics::__private::intern_label_value(#key, #value)) }
+    });
+    let labels = static_labels.chain(dynamic_labels);
+
+    // Custom buckets are registered so that exporters which can only configure buckets per metric include them
+    let (buckets, buckets_definition) = match &args.buckets {
+        Some(buckets) => {
+            let buckets = match buckets {
+                Buckets::Preset(preset) => {
+                    let preset =
+                        quote::format_ident!("{}_HISTOGRAM_BUCKETS", preset.to_uppercase());
+                    quote! { autometrics::__private::#preset }
+                }
+                Buckets::Custom(buckets) => quote! { &[#(#buckets),*] as &'static [f64] },
+            };
+            let buckets_definition = quote! {
+                {
+                    // This uses the same linkme "distributed slice" approach as the alert definitions
+                    #[autometrics::__private::linkme::distributed_slice(autometrics::__private::HISTOGRAM_BUCKETS)]
+                    #[linkme(crate = autometrics::__private::linkme)]
+                    static AUTOMETRICS_HISTOGRAM_BUCKETS: &'static [f64] = #buckets;
+                }
+            };
+            (quote! { Some(#buckets) }, buckets_definition)
+        }
+        None => (quote! { None }, TokenStream::new()),
+    };
 
     // The PROMETHEUS_URL can be configured by passing the environment variable during build time
     let prometheus_url =
@@ -146,12 +269,16 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
     // Build the documentation we'll add to the function's RustDocs
     let metrics_docs = create_metrics_docs(&prometheus_url, &function_name, track_concurrency);
 
-    // Wrap the body of the original function, using a slightly different approach based on whether the function is async
+    let result_type = result_type(&sig);
+
+    // Wrap the body of the original function, using a slightly different approach based on whether the function is async.
+    // Note that if the future returned by an async function is dropped before it completes,
+    // the tracker is dropped along with it and records the call as cancelled.
     let call_function = if sig.asyncness.is_some() {
         quote! {
             autometrics::__private::CALLER.scope(#function_name, async move {
                 #block
-            }).await
+            })
         }
     } else {
         quote! {
@@ -161,10 +288,63 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
         }
     };
 
+    // With the tracing feature, the function runs inside of a span named after the function
+    #[cfg(feature = "tracing")]
+    let (span_definition, call_function, record_span) = {
+        let span_definition = quote! {
+            let __autometrics_span = {
+                use autometrics::__private::{str_replace, tracing};
+                const module_label: &'static str = str_replace!(module_path!(), "::", ".");
+                tracing::info_span!(
+                    #function_name,
+                    function = #function_name,
+                    module = module_label,
+                    caller = autometrics::__private::CALLER.get(),
+                    result = tracing::field::Empty,
+                    error = tracing::field::Empty,
+                )
+            };
+        };
+        let call_function = if sig.asyncness.is_some() {
+            quote! {
+                autometrics::__private::tracing::Instrument::instrument(#call_function, __autometrics_span.clone()).await
+            }
+        } else {
+            quote! {
+                {
+                    let _enter = __autometrics_span.enter();
+                    #call_function
+                }
+            }
+        };
+        let record_span = quote! {
+            {
+                use autometrics::__private::{
+                    record_span_result, GetSpanError, RecordSpanError, SkipGetSpanError, SkipSpanError,
+                    SpanError,
+                };
+                record_span_result(&__autometrics_span, &counter_labels);
+                if let Some(err) = (&result).__autometrics_span_error() {
+                    (&SpanError(err)).__autometrics_record_span_error(&__autometrics_span);
+                }
+            }
+        };
+        (span_definition, call_function, record_span)
+    };
+    #[cfg(not(feature = "tracing"))]
+    let (span_definition, call_function, record_span) = {
+        let call_function = if sig.asyncness.is_some() {
+            quote! { #call_function.await }
+        } else {
+            call_function
+        };
+        (TokenStream::new(), call_function, TokenStream::new())
+    };
+
     #[cfg(feature = "alerts")]
     let alert_definition = if let Some(alerts) = &args.alerts {
         let function_name_uppercase =
-            quote::format_ident!("AUTOMETRICS_{}", function_name.to_uppercase());
+            quote::format_ident!("AUTOMETRICS_{}", sig.ident.to_string().to_uppercase());
         let success_rate = if let Some(success_rate) = alerts.success_rate {
             let success_rate = success_rate.normalize().to_string();
             quote! { Some(#success_rate) }
@@ -181,28 +361,23 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
 
         quote! {
             {
-                use autometrics::__private::{Alert, METRICS};
-
-                // This is a bit nuts for 2 reasons:
-                // 1. For every function that has alert definition defined, we create a static record in a
-                //    distributed slice that is "gathered into a contiguous section
-                //    of the binary by the linker". We then iterate over this list of
-                //    instrumented functions to generate the alerts.
-                //    See https://github.com/dtolnay/linkme for how this "shenanigans" works
-                // 2. We are intentionally bypassing the normal API and typechecking of the `linkme` crate 😬.
-                //    Instead of calling the `linkme::distributed_slice!` macro, we are calling
-                //    the METRICS macro directly. This is because the distributed_slice macro
-                //    expands to code that calls an import from the linkme crate.
-                //    We're using this workaround because we don't want to require users of this
-                //    crate to also add `linkme` as a dependency.
-                METRICS! {
-                    static #function_name_uppercase: Alert = Alert {
-                        function: #function_name,
-                        module: module_label,
-                        success_rate: #success_rate,
-                        latency: #latency,
-                    };
-                }
+                use autometrics::__private::Alert;
+
+                // For every function that has alert definition defined, we create a static record in a
+                // distributed slice that is "gathered into a contiguous section
+                // of the binary by the linker". We then iterate over this list of
+                // instrumented functions to generate the alerts.
+                // See https://github.com/dtolnay/linkme for how this "shenanigans" works.
+                // The linkme crate is re-exported by autometrics, so that users of this
+                // crate do not need to add `linkme` as a dependency.
+                #[autometrics::__private::linkme::distributed_slice(autometrics::__private::METRICS)]
+                #[linkme(crate = autometrics::__private::linkme)]
+                static #function_name_uppercase: Alert = Alert {
+                    function: #function_name,
+                    module: module_label,
+                    success_rate: #success_rate,
+                    latency: #latency,
+                };
             }
         }
     } else {
@@ -226,7 +401,7 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
                 let result_label = #result_label;
                 // If the return type implements Into<&'static str>, attach that as a label
                 let value_type = (&result).__autometrics_static_str();
-                create_label_array(result_label, __autometrics_tracker.function(), __autometrics_tracker.module(), CALLER.get(), value_type)
+                create_label_array(result_label, __autometrics_tracker.function(), __autometrics_tracker.module(), CALLER.get(), value_type, __autometrics_tracker.labels())
             }
         }
     } else {
@@ -235,7 +410,7 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
         quote! {
             {
                 use autometrics::__private::{CALLER, GetLabels, GetLabelsFromResult};
-                (&result).__autometrics_get_labels(__autometrics_tracker.function(), __autometrics_tracker.module(), CALLER.get())
+                (&result).__autometrics_get_labels(__autometrics_tracker.function(), __autometrics_tracker.module(), CALLER.get(), __autometrics_tracker.labels())
             }
         }
     };
@@ -247,6 +422,10 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
         #[doc=#metrics_docs]
 
         #vis #sig {
+            let __autometrics_labels: [autometrics::__private::Label; #label_count] = [#(#labels),*];
+
+            #span_definition
+
             let __autometrics_tracker = {
                 use autometrics::__private::{AutometricsTracker, TrackMetrics, str_replace};
 
@@ -256,14 +435,20 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
 
                 #alert_definition
 
-                AutometricsTracker::start(#function_name, module_label, #track_concurrency)
+                #buckets_definition
+
+                // Caches the metrics for this function, so they only need to be looked up once
+                static AUTOMETRICS_CALL_SITE: autometrics::__private::CallSite = autometrics::__private::CallSite::new();
+
+                AutometricsTracker::start(#function_name, module_label, &__autometrics_labels, #buckets, Some(&AUTOMETRICS_CALL_SITE), #track_concurrency)
             };
 
-            let result = #call_function;
+            let result #result_type = #call_function;
 
             {
                 use autometrics::__private::TrackMetrics;
                 let counter_labels = #counter_labels;
+                #record_span
                 __autometrics_tracker.finish(&counter_labels);
             }
 
@@ -272,8 +457,34 @@ fn instrument_function(args: &Args, item: ItemFn) -> Result<TokenStream> {
     })
 }
 
+/// The type annotation for the variable that holds the function's return value.
+///
+/// Without it, the type cannot be inferred if the function body diverges, for example because it always panics.
+/// `impl Trait` and `!` cannot be used as the type of a variable, so those are left to be inferred.
+fn result_type(sig: &Signature) -> TokenStream {
+    match &sig.output {
+        ReturnType::Default => quote! { : () },
+        ReturnType::Type(_, ty)
+            if !matches!(**ty, Type::Never(_)) && !contains_impl_trait(ty.to_token_stream()) =>
+        {
+            quote! { : #ty }
+        }
+        ReturnType::Type(..) => TokenStream::new(),
+    }
+}
+
+fn contains_impl_trait(tokens: TokenStream) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => ident == "impl",
+        TokenTree::Group(group) => contains_impl_trait(group.stream()),
+        _ => false,
+    })
+}
+
 /// Add autometrics instrumentation to an entire impl block
 fn instrument_impl_block(args: &Args, mut item: ItemImpl) -> Result<TokenStream> {
+    let impl_name = impl_block_name(&item);
+
     // Replace all of the method items in place
     item.items = item
         .items
@@ -298,7 +509,7 @@ fn instrument_impl_block(args: &Args, mut item: ItemImpl) -> Result<TokenStream>
                     sig: method.sig,
                     block: Box::new(method.block),
                 };
-                let tokens = match instrument_function(args, item_fn) {
+                let tokens = match instrument_function(args, item_fn, Some(&impl_name)) {
                     Ok(tokens) => tokens,
                     Err(err) => err.to_compile_error(),
                 };
@@ -311,38 +522,60 @@ fn instrument_impl_block(args: &Args, mut item: ItemImpl) -> Result<TokenStream>
     Ok(quote! { #item })
 }
 
+/// Get the name used to qualify the methods in an impl block,
+/// such as `MyStruct` or `<MyStruct as MyTrait>`
+fn impl_block_name(item: &ItemImpl) -> String {
+    let self_type = match item.self_ty.as_ref() {
+        Type::Path(type_path) => path_name(&type_path.path),
+        other => quote! { #other }.to_string().replace(' ', ""),
+    };
+
+    match &item.trait_ {
+        Some((_, trait_path, _)) => format!("<{self_type} as {}>", path_name(trait_path)),
+        None => self_type,
+    }
+}
+
+/// Use the last segment of the path without any generic arguments,
+/// so that `crate::db::Db<T>` becomes `Db`
+fn path_name(path: &Path) -> String {
+    path.segments
+        .last()
+        .map(|segment| segment.ident.to_string())
+        .unwrap_or_default()
+}
+
 /// Create Prometheus queries for the generated metric and
 /// package them up into a RustDoc string
 fn create_metrics_docs(prometheus_url: &str, function: &str, track_concurrency: bool) -> String {
-    let request_rate = request_rate_query(&COUNTER_NAME_PROMETHEUS, "function", &function);
+    let request_rate = request_rate_query(COUNTER_NAME_PROMETHEUS, "function", function);
     let request_rate_url = make_prometheus_url(
-        &prometheus_url,
+        prometheus_url,
         &request_rate,
         &format!(
             "Rate of calls to the `{function}` function per second, averaged over 5 minute windows"
         ),
     );
-    let callee_request_rate = request_rate_query(&COUNTER_NAME_PROMETHEUS, "caller", &function);
-    let callee_request_rate_url = make_prometheus_url(&prometheus_url, &callee_request_rate, &format!("Rate of calls to functions called by `{function}` per second, averaged over 5 minute windows"));
+    let callee_request_rate = request_rate_query(COUNTER_NAME_PROMETHEUS, "caller", function);
+    let callee_request_rate_url = make_prometheus_url(prometheus_url, &callee_request_rate, &format!("Rate of calls to functions called by `{function}` per second, averaged over 5 minute windows"));
 
-    let error_ratio = &error_ratio_query(&COUNTER_NAME_PROMETHEUS, "function", &function);
-    let error_ratio_url = make_prometheus_url(&prometheus_url, &error_ratio, &format!("Percentage of calls to the `{function}` function that return errors, averaged over 5 minute windows"));
-    let callee_error_ratio = &error_ratio_query(&COUNTER_NAME_PROMETHEUS, "caller", &function);
-    let callee_error_ratio_url = make_prometheus_url(&prometheus_url, &callee_error_ratio, &format!("Percentage of calls to functions called by `{function}` that return errors, averaged over 5 minute windows"));
+    let error_ratio = &error_ratio_query(COUNTER_NAME_PROMETHEUS, "function", function);
+    let error_ratio_url = make_prometheus_url(prometheus_url, error_ratio, &format!("Percentage of calls to the `{function}` function that return errors, averaged over 5 minute windows"));
+    let callee_error_ratio = &error_ratio_query(COUNTER_NAME_PROMETHEUS, "caller", function);
+    let callee_error_ratio_url = make_prometheus_url(prometheus_url, callee_error_ratio, &format!("Percentage of calls to functions called by `{function}` that return errors, averaged over 5 minute windows"));
 
-    let latency = latency_query(&HISTOGRAM_BUCKET_NAME_PROMETHEUS, "function", &function);
+    let latency = latency_query(HISTOGRAM_NAME_PROMETHEUS, "function", function);
     let latency_url = make_prometheus_url(
-        &prometheus_url,
+        prometheus_url,
         &latency,
         &format!("95th and 99th percentile latencies (in seconds) for the `{function}` function"),
     );
 
     // Only include the concurrent calls query if the user has enabled it for this function
     let concurrent_calls_doc = if track_concurrency {
-        let concurrent_calls =
-            concurrent_calls_query(&GAUGE_NAME_PROMETHEUS, "function", &function);
+        let concurrent_calls = concurrent_calls_query(GAUGE_NAME_PROMETHEUS, "function", function);
         let concurrent_calls_url = make_prometheus_url(
-            &prometheus_url,
+            prometheus_url,
             &concurrent_calls,
             &format!("Concurrent calls to the `{function}` function"),
         );
@@ -383,19 +616,37 @@ fn make_prometheus_url(url: &str, query: &str, comment: &str) -> String {
     url
 }
 
+/// The counter's samples are named with a `_total` suffix when they are scraped in the OpenMetrics format,
+/// so the queries match the counter by a regular expression on its name
 fn request_rate_query(counter_name: &str, label_key: &str, label_value: &str) -> String {
-    format!("sum by (function, module) (rate({counter_name}{{{label_key}=\"{label_value}\"}}[5m]))")
+    format!("sum by (function, module) (rate({{__name__=~\"{counter_name}(_total)?\",{label_key}=\"{label_value}\"}}[5m]))")
 }
 
 fn error_ratio_query(counter_name: &str, label_key: &str, label_value: &str) -> String {
     let request_rate = request_rate_query(counter_name, label_key, label_value);
-    format!("sum by (function, module) (rate({counter_name}{{{label_key}=\"{label_value}\",result=\"error\"}}[5m])) /
+    format!("sum by (function, module) (rate({{__name__=~\"{counter_name}(_total)?\",{label_key}=\"{label_value}\",result=~\"error|panic\"}}[5m])) /
 {request_rate}", )
 }
 
-fn latency_query(bucket_name: &str, label_key: &str, label_value: &str) -> String {
+/// The histogram is named with a `_seconds` suffix when it is scraped in the OpenMetrics format,
+/// which requires the name to end with the unit
+#[cfg(not(feature = "native-histograms"))]
+fn latency_query(histogram_name: &str, label_key: &str, label_value: &str) -> String {
+    let latency = format!(
+        "sum by (le, function, module) (rate({{__name__=~\"{histogram_name}(_seconds)?_bucket\",{label_key}=\"{label_value}\"}}[5m]))"
+    );
+    format!(
+        "histogram_quantile(0.99, {latency}) or
+histogram_quantile(0.95, {latency})"
+    )
+}
+
+/// Native histograms are queried using the histogram's name rather than the `_bucket` series,
+/// and the buckets are aggregated without the `le` label
+#[cfg(feature = "native-histograms")]
+fn latency_query(histogram_name: &str, label_key: &str, label_value: &str) -> String {
     let latency = format!(
-        "sum by (le, function, module) (rate({bucket_name}{{{label_key}=\"{label_value}\"}}[5m]))"
+        "sum by (function, module) (rate({histogram_name}{{{label_key}=\"{label_value}\"}}[5m]))"
     );
     format!(
         "histogram_quantile(0.99, {latency}) or
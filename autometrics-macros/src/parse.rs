@@ -1,5 +1,5 @@
 use syn::parse::{Parse, ParseStream};
-use syn::{Expr, ItemFn, ItemImpl, Result, Token};
+use syn::{Expr, ExprArray, Ident, ItemFn, ItemImpl, Lit, LitStr, Result, Token};
 
 /// Autometrics can be applied to individual functions or to
 /// (all of the methods within) impl blocks.
@@ -24,6 +24,9 @@ pub(crate) struct Args {
     pub track_concurrency: bool,
     pub ok_if: Option<Expr>,
     pub error_if: Option<Expr>,
+    pub labels: Vec<StaticLabel>,
+    pub dynamic_labels: Vec<DynamicLabel>,
+    pub buckets: Option<Buckets>,
 
     #[cfg(feature = "alerts")]
     pub alerts: Option<alerts::Alerts>,
@@ -36,6 +39,10 @@ mod kw {
     syn::custom_keyword!(latency);
     syn::custom_keyword!(ok_if);
     syn::custom_keyword!(error_if);
+    syn::custom_keyword!(labels);
+    syn::custom_keyword!(label);
+    syn::custom_keyword!(dynamic_labels);
+    syn::custom_keyword!(buckets);
 }
 
 impl Parse for Args {
@@ -64,6 +71,23 @@ impl Parse for Args {
                 }
                 let error_if = input.parse::<ExprArg<kw::error_if>>()?;
                 args.error_if = Some(error_if.value);
+            } else if lookahead.peek(kw::labels) {
+                let _ = input.parse::<kw::labels>()?;
+                args.labels.extend(parse_labels(input)?);
+            } else if lookahead.peek(kw::label) {
+                let _ = input.parse::<kw::label>()?;
+                args.dynamic_labels.extend(parse_labels(input)?);
+            } else if lookahead.peek(kw::dynamic_labels) {
+                // `dynamic_labels` is another name for `label`
+                let _ = input.parse::<kw::dynamic_labels>()?;
+                args.dynamic_labels.extend(parse_labels(input)?);
+            } else if lookahead.peek(kw::buckets) {
+                if args.buckets.is_some() {
+                    return Err(input.error("expected only a single `buckets` argument"));
+                }
+                let _ = input.parse::<kw::buckets>()?;
+                let _ = input.parse::<Token![=]>()?;
+                args.buckets = Some(input.parse()?);
             } else if lookahead.peek(kw::alerts) {
                 #[cfg(feature = "alerts")]
                 {
@@ -81,6 +105,13 @@ impl Parse for Args {
                 return Err(lookahead.error());
             }
         }
+
+        validate_label_keys(
+            args.labels
+                .iter()
+                .map(|label| &label.key)
+                .chain(args.dynamic_labels.iter().map(|label| &label.key)),
+        )?;
         Ok(args)
     }
 }
@@ -102,6 +133,143 @@ impl<T: Parse> Parse for ExprArg<T> {
     }
 }
 
+/// Label keys that are already used by autometrics and therefore cannot be
+/// overwritten by user-defined labels
+const RESERVED_LABEL_KEYS: [&str; 7] = [
+    "function",
+    "module",
+    "caller",
+    "caller_service",
+    "result",
+    "ok",
+    "error",
+];
+
+/// A user-defined label that is attached to all of the metrics for a function
+pub(crate) struct LabelArg<V> {
+    pub key: Ident,
+    pub value: V,
+}
+
+/// A label with a fixed value
+pub(crate) type StaticLabel = LabelArg<LitStr>;
+
+/// A label whose value is computed from the function's arguments when the function is called
+pub(crate) type DynamicLabel = LabelArg<Expr>;
+
+impl<V: Parse> Parse for LabelArg<V> {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let key = input.parse::<Ident>()?;
+        let _ = input.parse::<Token![=]>()?;
+        let value = input.parse::<V>()?;
+        Ok(LabelArg { key, value })
+    }
+}
+
+// Parse labels in the form labels(team = "payments", tier = "critical")
+// or label(tenant = req.tenant_id())
+fn parse_labels<V: Parse>(input: ParseStream) -> Result<Vec<LabelArg<V>>> {
+    let content;
+    let _ = syn::parenthesized!(content in input);
+
+    let labels: Vec<LabelArg<V>> = content
+        .parse_terminated::<LabelArg<V>, Token![,]>(LabelArg::parse)?
+        .into_iter()
+        .collect();
+    if labels.is_empty() {
+        return Err(content.error("expected at least one label"));
+    }
+    Ok(labels)
+}
+
+/// Ensure that the user-defined label keys are unique and do not overwrite the autometrics labels
+fn validate_label_keys<'a>(keys: impl Iterator<Item = &'a Ident>) -> Result<()> {
+    let mut seen: Vec<&Ident> = Vec::new();
+    for key in keys {
+        let key_string = key.to_string();
+        if RESERVED_LABEL_KEYS.contains(&key_string.as_str()) {
+            return Err(syn::Error::new(
+                key.span(),
+                format!("the label key `{key_string}` is reserved by autometrics"),
+            ));
+        }
+        if seen.contains(&key) {
+            return Err(syn::Error::new(
+                key.span(),
+                format!("duplicate label key `{key_string}`"),
+            ));
+        }
+        seen.push(key);
+    }
+    Ok(())
+}
+
+/// The names of the histogram bucket presets defined in the autometrics crate
+const BUCKET_PRESETS: [&str; 3] = ["default", "micro", "batch"];
+
+/// Histogram buckets for a single function
+pub(crate) enum Buckets {
+    /// The name of one of the presets, such as `micro` or `batch`
+    Preset(String),
+    /// The upper bounds of the buckets, in seconds
+    Custom(Vec<f64>),
+}
+
+// Parse buckets in the form buckets = "micro" or buckets = [0.0001, 0.001, 0.01]
+impl Parse for Buckets {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let lookahead = input.lookahead1();
+        if lookahead.peek(LitStr) {
+            let preset = input.parse::<LitStr>()?;
+            if !BUCKET_PRESETS.contains(&preset.value().as_str()) {
+                return Err(syn::Error::new(
+                    preset.span(),
+                    format!(
+                        "unknown histogram buckets preset, expected one of: {}",
+                        BUCKET_PRESETS.join(", ")
+                    ),
+                ));
+            }
+            Ok(Buckets::Preset(preset.value()))
+        } else if lookahead.peek(syn::token::Bracket) {
+            let array = input.parse::<ExprArray>()?;
+            let mut buckets: Vec<f64> = Vec::with_capacity(array.elems.len());
+            for elem in &array.elems {
+                let bucket = match elem {
+                    Expr::Lit(expr) => match &expr.lit {
+                        Lit::Float(lit) => lit.base10_parse::<f64>()?,
+                        Lit::Int(lit) => lit.base10_parse::<f64>()?,
+                        _ => return Err(syn::Error::new_spanned(elem, "expected a number")),
+                    },
+                    _ => return Err(syn::Error::new_spanned(elem, "expected a number")),
+                };
+                if !bucket.is_finite() || bucket <= 0.0 {
+                    return Err(syn::Error::new_spanned(
+                        elem,
+                        "histogram buckets must be positive numbers",
+                    ));
+                }
+                if buckets.last().is_some_and(|last| *last >= bucket) {
+                    return Err(syn::Error::new_spanned(
+                        elem,
+                        "histogram buckets must be in increasing order",
+                    ));
+                }
+                buckets.push(bucket);
+            }
+            if buckets.is_empty() {
+                return Err(syn::Error::new_spanned(
+                    array,
+                    "expected at least one histogram bucket",
+                ));
+            }
+            Ok(Buckets::Custom(buckets))
+        } else {
+            Err(lookahead.error())
+        }
+    }
+}
+
 #[cfg(feature = "alerts")]
 mod alerts {
     use super::*;
@@ -0,0 +1,61 @@
+//! Measures the overhead that the instrumentation adds to every function call.
+//!
+//! To compare two versions of the crate, save a baseline on the first one and compare against it on the second:
+//!
+//! ```sh
+//! cargo bench --bench tracker --features prometheus-exporter -- --save-baseline before
+//! # switch to the other version
+//! cargo bench --bench tracker --features prometheus-exporter -- --baseline before
+//! ```
+
+use autometrics::autometrics;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[autometrics]
+fn instrumented_add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[autometrics]
+fn instrumented_result(a: i32, b: i32) -> Result<i32, ()> {
+    Ok(a + b)
+}
+
+#[autometrics(track_concurrency)]
+fn instrumented_concurrency(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[autometrics(labels(team = "payments"))]
+fn instrumented_labels(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+fn function_calls(c: &mut Criterion) {
+    // Record the metrics with a real exporter, because the default OpenTelemetry meter provider does nothing
+    #[cfg(feature = "prometheus-exporter")]
+    let _exporter = autometrics::global_metrics_exporter();
+
+    let mut group = c.benchmark_group("function_call");
+    group.bench_function("baseline", |b| b.iter(|| add(black_box(1), black_box(2))));
+    group.bench_function("instrumented", |b| {
+        b.iter(|| instrumented_add(black_box(1), black_box(2)))
+    });
+    group.bench_function("instrumented_result", |b| {
+        b.iter(|| instrumented_result(black_box(1), black_box(2)))
+    });
+    group.bench_function("track_concurrency", |b| {
+        b.iter(|| instrumented_concurrency(black_box(1), black_box(2)))
+    });
+    group.bench_function("custom_labels", |b| {
+        b.iter(|| instrumented_labels(black_box(1), black_box(2)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, function_calls);
+criterion_main!(benches);
@@ -245,13 +245,13 @@ impl Objective for SuccessRateObjective {
     fn error_query(&self, window: &str) -> String {
         let function = self.function();
         let module = self.module();
-        format!("sum(rate(function_calls_count{{function=\"{function}\",module=\"{module}\",result=\"error\"}}[{window}]))")
+        format!("sum(rate({{__name__=~\"function_calls_count(_total)?\",function=\"{function}\",module=\"{module}\",result=~\"error|panic\"}}[{window}]))")
     }
 
     fn total_query(&self, window: &str) -> String {
         let function = self.function();
         let module = self.module();
-        format!("sum(rate(function_calls_count{{function=\"{function}\",module=\"{module}\"}}[{window}]))")
+        format!("sum(rate({{__name__=~\"function_calls_count(_total)?\",function=\"{function}\",module=\"{module}\"}}[{window}]))")
     }
 }
 
@@ -279,17 +279,45 @@ impl Objective for LatencyObjective {
         self.latency_objective
     }
 
+    #[cfg(not(feature = "native-histograms"))]
     fn error_query(&self, window: &str) -> String {
         let function = self.function();
         let module = self.module();
         let latency_threshold = self.latency_threshold;
-        format!("(sum(rate(function_calls_duration_bucket{{function=\"{function}\",module=\"{module}\"}}[{window}])) \
-                - sum(rate(function_calls_duration_bucket{{le=\"{latency_threshold}\",function=\"{function}\",module=\"{module}\"}}[{window}])))")
+        format!("(sum(rate({{__name__=~\"function_calls_duration(_seconds)?_bucket\",function=\"{function}\",module=\"{module}\"}}[{window}])) \
+                - sum(rate({{__name__=~\"function_calls_duration(_seconds)?_bucket\",le=\"{latency_threshold}\",function=\"{function}\",module=\"{module}\"}}[{window}])))")
     }
 
+    #[cfg(not(feature = "native-histograms"))]
     fn total_query(&self, window: &str) -> String {
         let function = self.function();
         let module = self.module();
-        format!("sum(rate(function_calls_duration_bucket{{function=\"{function}\",module=\"{module}\"}}[{window}]))")
+        format!("sum(rate({{__name__=~\"function_calls_duration(_seconds)?_bucket\",function=\"{function}\",module=\"{module}\"}}[{window}]))")
+    }
+
+    /// With native histograms, the fraction of calls that took longer than the threshold
+    /// can be estimated for any threshold, rather than only for the bucket boundaries
+    #[cfg(feature = "native-histograms")]
+    fn error_query(&self, window: &str) -> String {
+        let latency_threshold = self.latency_threshold;
+        let rate = self.native_histogram_rate(window);
+        format!(
+            "(histogram_count({rate}) * (1 - histogram_fraction(0, {latency_threshold}, {rate})))"
+        )
+    }
+
+    #[cfg(feature = "native-histograms")]
+    fn total_query(&self, window: &str) -> String {
+        let rate = self.native_histogram_rate(window);
+        format!("histogram_count({rate})")
+    }
+}
+
+#[cfg(feature = "native-histograms")]
+impl LatencyObjective {
+    fn native_histogram_rate(&self, window: &str) -> String {
+        let function = self.function;
+        let module = self.module;
+        format!("sum(rate(function_calls_duration{{function=\"{function}\",module=\"{module}\"}}[{window}]))")
     }
 }
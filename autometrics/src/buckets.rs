@@ -0,0 +1,74 @@
+use linkme::distributed_slice;
+#[cfg(any(feature = "prometheus-exporter", feature = "otlp-exporter"))]
+use once_cell::sync::OnceCell;
+
+/// The default histogram buckets, which suit most HTTP handlers and other
+/// functions that take between 10 milliseconds and 1 second
+pub const DEFAULT_HISTOGRAM_BUCKETS: &[f64] =
+    &[0.01, 0.025, 0.05, 0.075, 0.1, 0.15, 0.2, 0.35, 0.5, 1.0];
+
+/// Histogram buckets for functions that take between 10 microseconds and 10 milliseconds,
+/// such as cache lookups. Use these with `#[autometrics(buckets = "micro")]`.
+pub const MICRO_HISTOGRAM_BUCKETS: &[f64] = &[
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01,
+];
+
+/// Histogram buckets for functions that take between 1 second and 1 hour,
+/// such as batch jobs. Use these with `#[autometrics(buckets = "batch")]`.
+pub const BATCH_HISTOGRAM_BUCKETS: &[f64] = &[
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 3600.0,
+];
+
+/// The default buckets configured using the `PrometheusExporterBuilder` or `OtlpExporterBuilder`
+#[cfg(any(feature = "prometheus-exporter", feature = "otlp-exporter"))]
+static CONFIGURED_DEFAULT_BUCKETS: OnceCell<&'static [f64]> = OnceCell::new();
+
+/// Returns the buckets used for functions that do not configure their own
+#[cfg(any(feature = "native", feature = "prometheus"))]
+pub(crate) fn default_histogram_buckets() -> &'static [f64] {
+    #[cfg(any(feature = "prometheus-exporter", feature = "otlp-exporter"))]
+    if let Some(buckets) = CONFIGURED_DEFAULT_BUCKETS.get().copied() {
+        return buckets;
+    }
+    DEFAULT_HISTOGRAM_BUCKETS
+}
+
+/// Set the buckets used for functions that do not configure their own.
+///
+/// This only affects metrics libraries that configure buckets per time series,
+/// and only function calls tracked after this is called.
+#[cfg(any(feature = "prometheus-exporter", feature = "otlp-exporter"))]
+pub(crate) fn set_default_histogram_buckets(buckets: Vec<f64>) {
+    let _ = CONFIGURED_DEFAULT_BUCKETS.set(Vec::leak(buckets));
+}
+
+// This "distributed slice" is used to collect the histogram buckets of every function
+// that uses the `buckets` argument of the `autometrics` macro.
+// The opentelemetry and metrics crates only allow buckets to be configured per metric,
+// rather than per time series, so the exporter configures them with all of the
+// buckets used by any function. See https://github.com/dtolnay/linkme for how this works.
+#[doc(hidden)]
+#[distributed_slice]
+pub static HISTOGRAM_BUCKETS: [&'static [f64]] = [..];
+
+/// Returns the given default buckets combined with the buckets configured for any instrumented function,
+/// sorted and without duplicates
+#[cfg(any(feature = "prometheus-exporter", feature = "otlp-exporter"))]
+pub(crate) fn all_histogram_buckets(default_buckets: &[f64]) -> Vec<f64> {
+    let mut buckets: Vec<f64> = default_buckets
+        .iter()
+        .chain(HISTOGRAM_BUCKETS.iter().flat_map(|buckets| buckets.iter()))
+        .copied()
+        .collect();
+    buckets.sort_by(|a, b| a.total_cmp(b));
+    buckets.dedup();
+    buckets
+}
+
+/// Whether the buckets are finite numbers in increasing order
+#[cfg(any(feature = "prometheus-exporter", feature = "otlp-exporter"))]
+pub(crate) fn are_valid_buckets(buckets: &[f64]) -> bool {
+    !buckets.is_empty()
+        && buckets.iter().all(|bucket| bucket.is_finite())
+        && buckets.windows(2).all(|pair| pair[0] < pair[1])
+}
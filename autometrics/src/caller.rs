@@ -0,0 +1,72 @@
+use crate::__private::CALLER;
+use std::future::Future;
+
+/// The name of the instrumented function that is currently running, or the empty string
+pub(crate) fn current_caller() -> &'static str {
+    CALLER.try_with(|caller| *caller).unwrap_or("")
+}
+
+/// Run the future with the `caller` of the current context.
+///
+/// The caller is tracked in a task-local value, which is lost when work is moved
+/// to another task, for example using `tokio::spawn`. Wrap the future before it is
+/// spawned so that instrumented functions called by the spawned task still report
+/// the function that spawned it as their caller.
+///
+/// ```rust,ignore
+/// #[autometrics]
+/// async fn handle_request() {
+///     // `send_email` will report `handle_request` as its caller
+///     tokio::spawn(autometrics::with_caller(send_email()));
+/// }
+/// ```
+pub fn with_caller<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    CALLER.scope(current_caller(), future)
+}
+
+/// Wrap the closure so that it runs with the `caller` of the current context.
+///
+/// This is the equivalent of [`with_caller`] for closures that are run on another thread,
+/// for example using `std::thread::spawn`, `tokio::task::spawn_blocking` or `rayon::spawn`.
+///
+/// ```rust,ignore
+/// #[autometrics]
+/// fn handle_request() {
+///     // `send_email` will report `handle_request` as its caller
+///     std::thread::spawn(autometrics::with_caller_fn(|| send_email()));
+/// }
+/// ```
+pub fn with_caller_fn<F, R>(f: F) -> impl FnOnce() -> R
+where
+    F: FnOnce() -> R,
+{
+    let caller = current_caller();
+    move || CALLER.sync_scope(caller, f)
+}
+
+/// Spawn a new Tokio task that keeps the `caller` of the current context.
+///
+/// This is a drop-in replacement for `tokio::spawn`.
+#[cfg(feature = "tokio")]
+#[track_caller]
+pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(with_caller(future))
+}
+
+/// Run the blocking closure on Tokio's blocking thread pool,
+/// keeping the `caller` of the current context.
+///
+/// This is a drop-in replacement for `tokio::task::spawn_blocking`.
+#[cfg(feature = "tokio")]
+#[track_caller]
+pub fn spawn_blocking<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(with_caller_fn(f))
+}
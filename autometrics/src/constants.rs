@@ -11,9 +11,15 @@ pub(crate) const GAUGE_DESCRIPTION: &str =
     "Autometrics gauge for tracking concurrent function calls";
 
 // Labels
-pub(crate) const FUNCTION_KEY: &'static str = "function";
-pub(crate) const MODULE_KEY: &'static str = "module";
-pub(crate) const CALLER_KEY: &'static str = "caller";
-pub(crate) const RESULT_KEY: &'static str = "result";
-pub(crate) const OK_KEY: &'static str = "ok";
-pub(crate) const ERROR_KEY: &'static str = "error";
+pub(crate) const FUNCTION_KEY: &str = "function";
+pub(crate) const MODULE_KEY: &str = "module";
+pub(crate) const CALLER_KEY: &str = "caller";
+pub(crate) const CALLER_SERVICE_KEY: &str = "caller_service";
+pub(crate) const RESULT_KEY: &str = "result";
+pub(crate) const OK_KEY: &str = "ok";
+pub(crate) const ERROR_KEY: &str = "error";
+pub(crate) const PANIC_KEY: &str = "panic";
+pub(crate) const CANCELLED_KEY: &str = "cancelled";
+
+// Dynamic label values beyond the per-key limit are recorded with this value
+pub(crate) const OVERFLOW_LABEL_VALUE: &str = "__autometrics_other";
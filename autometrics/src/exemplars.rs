@@ -0,0 +1,118 @@
+//! Exemplars link the function call counter and latency histogram to a trace of one of the calls.
+//!
+//! The trace and span IDs are taken from the OpenTelemetry context that is active when the
+//! instrumented function is called. Only sampled traces are used, because the others are never exported.
+//! The native metrics store their own exemplars, and the exemplars of the OpenTelemetry metrics
+//! are kept in the [`store`] until the Prometheus exporter encodes them.
+
+use crate::text::{since_epoch, Timestamp};
+use opentelemetry_api::{trace::TraceContextExt, Context};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[cfg(all(
+    feature = "opentelemetry",
+    feature = "prometheus-exporter",
+    not(feature = "native")
+))]
+pub(crate) mod store;
+
+/// The IDs of the span that was active when a function call started
+#[derive(Clone, Copy)]
+pub(crate) struct TraceIds {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+impl TraceIds {
+    pub(crate) fn from_context(context: &Context) -> Option<Self> {
+        let span = context.span();
+        let span_context = span.span_context();
+        if span_context.is_valid() && span_context.is_sampled() {
+            Some(TraceIds {
+                trace_id: span_context.trace_id().to_bytes(),
+                span_id: span_context.span_id().to_bytes(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Exemplar {
+    trace: TraceIds,
+    pub(crate) value: f64,
+    /// The time since the Unix epoch
+    pub(crate) timestamp: Duration,
+}
+
+impl Exemplar {
+    pub(crate) fn new(trace: TraceIds, value: f64) -> Self {
+        Exemplar {
+            trace,
+            value,
+            timestamp: since_epoch(),
+        }
+    }
+
+    /// The exemplar's labels, with the IDs encoded in hex like in the W3C trace context
+    pub(crate) fn labels(&self) -> [(&'static str, String); 2] {
+        [
+            ("trace_id", hex(&self.trace.trace_id)),
+            ("span_id", hex(&self.trace.span_id)),
+        ]
+    }
+}
+
+/// Writes the exemplar in the OpenMetrics text format, including the leading `#`
+impl fmt::Display for Exemplar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [(trace_key, trace_id), (span_key, span_id)] = self.labels();
+        write!(
+            f,
+            " # {{{trace_key}=\"{trace_id}\",{span_key}=\"{span_id}\"}} {:?} {}",
+            self.value,
+            Timestamp(self.timestamp)
+        )
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Holds the most recent exemplar of a counter or histogram bucket
+#[derive(Default)]
+pub(crate) struct ExemplarSlot(Mutex<Option<Exemplar>>);
+
+impl ExemplarSlot {
+    /// Replace the exemplar, unless another thread is replacing it at the same time.
+    /// Only one recent exemplar is needed, so recording a call never waits for the lock.
+    pub(crate) fn set(&self, exemplar: Exemplar) {
+        if let Ok(mut slot) = self.0.try_lock() {
+            *slot = Some(exemplar);
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<Exemplar> {
+        match self.0.lock() {
+            Ok(slot) => *slot,
+            Err(_) => None,
+        }
+    }
+}
+
+/// A sample value followed by its exemplar, if it has one
+pub(crate) struct WithExemplar<T>(pub(crate) T, pub(crate) Option<Exemplar>);
+
+impl<T: fmt::Display> fmt::Display for WithExemplar<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)?;
+        if let Some(exemplar) = &self.1 {
+            exemplar.fmt(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! The OpenTelemetry SDK does not support exemplars, so the OpenTelemetry tracker keeps the most recent
+//! exemplars of each time series here, and the Prometheus exporter adds them to the metrics it gathers
+//! from OpenTelemetry when it encodes them in the OpenMetrics format.
+
+use super::{Exemplar, ExemplarSlot, TraceIds};
+use crate::constants::FUNCTION_KEY;
+use crate::labels::Label;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// The exemplars are only kept once the Prometheus exporter that encodes them has been initialized
+static STORE: OnceCell<Store> = OnceCell::new();
+
+type Series<T> = RwLock<HashMap<Vec<Label>, Arc<T>>>;
+
+/// The labels and exemplars of the time series of each function
+type ByFunction<T> = HashMap<&'static str, Vec<(Vec<Label>, T)>>;
+
+struct Store {
+    /// The upper bounds of the exporter's histogram buckets, not including `+Inf`
+    buckets: Vec<f64>,
+    counters: Series<ExemplarSlot>,
+    /// The most recent exemplar of each bucket, including `+Inf`
+    histograms: Series<[ExemplarSlot]>,
+}
+
+/// Start keeping the exemplars, for the histogram buckets that the exporter was configured with
+pub(crate) fn init(buckets: Vec<f64>) {
+    let _ = STORE.set(Store {
+        buckets,
+        counters: Default::default(),
+        histograms: Default::default(),
+    });
+}
+
+/// Keep the exemplars of a function call for the call counter and latency histogram
+pub(crate) fn record(
+    trace: TraceIds,
+    counter_labels: &[Label],
+    histogram_labels: &[Label],
+    seconds: f64,
+) {
+    let store = match STORE.get() {
+        Some(store) => store,
+        None => return,
+    };
+
+    get_or_insert(&store.counters, counter_labels, Default::default).set(Exemplar::new(trace, 1.0));
+
+    let bucket = store.buckets.partition_point(|bound| *bound < seconds);
+    get_or_insert(&store.histograms, histogram_labels, || {
+        (0..=store.buckets.len())
+            .map(|_| ExemplarSlot::default())
+            .collect()
+    })[bucket]
+        .set(Exemplar::new(trace, seconds));
+}
+
+fn get_or_insert<T: ?Sized>(
+    series: &Series<T>,
+    labels: &[Label],
+    create: impl FnOnce() -> Arc<T>,
+) -> Arc<T> {
+    if let Some(exemplars) = series
+        .read()
+        .expect("autometrics exemplars lock poisoned")
+        .get(labels)
+    {
+        return exemplars.clone();
+    }
+    series
+        .write()
+        .expect("autometrics exemplars lock poisoned")
+        .entry(labels.to_vec())
+        .or_insert_with(create)
+        .clone()
+}
+
+/// The exemplars of all time series at the time they are encoded, grouped by function
+#[derive(Default)]
+pub(crate) struct Exemplars {
+    buckets: Vec<f64>,
+    counters: ByFunction<Exemplar>,
+    histograms: ByFunction<Vec<Option<Exemplar>>>,
+}
+
+impl Exemplars {
+    pub(crate) fn get() -> Self {
+        let store = match STORE.get() {
+            Some(store) => store,
+            None => return Exemplars::default(),
+        };
+
+        let mut exemplars = Exemplars {
+            buckets: store.buckets.clone(),
+            ..Default::default()
+        };
+        for (labels, slot) in store
+            .counters
+            .read()
+            .expect("autometrics exemplars lock poisoned")
+            .iter()
+        {
+            if let Some(exemplar) = slot.get() {
+                exemplars
+                    .counters
+                    .entry(function(labels))
+                    .or_default()
+                    .push((labels.clone(), exemplar));
+            }
+        }
+        for (labels, slots) in store
+            .histograms
+            .read()
+            .expect("autometrics exemplars lock poisoned")
+            .iter()
+        {
+            exemplars
+                .histograms
+                .entry(function(labels))
+                .or_default()
+                .push((
+                    labels.clone(),
+                    slots.iter().map(ExemplarSlot::get).collect(),
+                ));
+        }
+        exemplars
+    }
+
+    /// The exemplar of the call counter time series with the given labels
+    pub(crate) fn counter(&self, labels: &[(&str, &str)]) -> Option<Exemplar> {
+        find(&self.counters, labels).copied()
+    }
+
+    /// The exemplar of the histogram bucket with the given upper bound
+    pub(crate) fn bucket(&self, labels: &[(&str, &str)], upper_bound: f64) -> Option<Exemplar> {
+        let bucket = if upper_bound == f64::INFINITY {
+            self.buckets.len()
+        } else {
+            // The histogram may have been recorded with other buckets by another metrics library
+            self.buckets
+                .iter()
+                .position(|bound| *bound == upper_bound)?
+        };
+        find(&self.histograms, labels)?[bucket]
+    }
+}
+
+fn function(labels: &[Label]) -> &'static str {
+    labels
+        .iter()
+        .find(|(key, _)| *key == FUNCTION_KEY)
+        .map_or("", |(_, value)| value)
+}
+
+/// Find the exemplars of the time series with the given labels.
+///
+/// The exporter adds the constant labels and the OpenTelemetry resource to the labels recorded by the tracker,
+/// so this uses the time series with the most labels that all have the same value (or are missing if empty).
+fn find<'a, T>(series: &'a ByFunction<T>, labels: &[(&str, &str)]) -> Option<&'a T> {
+    let value = |key: &str| {
+        labels
+            .iter()
+            .find(|(label_key, _)| *label_key == key)
+            .map_or("", |(_, value)| value)
+    };
+    series
+        .get(value(FUNCTION_KEY))?
+        .iter()
+        .filter(|(series_labels, _)| {
+            series_labels
+                .iter()
+                .all(|(key, series_value)| value(key) == *series_value)
+        })
+        .max_by_key(|(series_labels, _)| series_labels.len())
+        .map(|(_, exemplars)| exemplars)
+}
@@ -0,0 +1,169 @@
+//! A minimal HTTP/1.1 client for pushing metrics, so that the OTLP exporter and the Pushgateway
+//! client do not need to depend on an HTTP library or an async runtime.
+//!
+//! `https://` URLs are supported with the `tls` feature, which verifies the receiver's certificate
+//! with the Mozilla root certificates.
+
+#[cfg(feature = "tls")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "tls")]
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The parts of an `http://` or `https://` URL that are needed to send a request
+#[derive(Clone, Debug)]
+pub(crate) struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    pub(crate) fn parse(url: &str) -> Result<Self, String> {
+        let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err(format!("`{url}` is not an http:// or https:// URL")),
+        };
+        if tls && cfg!(not(feature = "tls")) {
+            return Err(format!(
+                "`{url}` uses https://, but TLS is only supported with the `tls` feature. \
+                 Enable it, or push to a local agent or collector over http:// and forward the metrics from there"
+            ));
+        }
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // IPv6 addresses contain colons, so the port must come after the closing bracket
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("`{url}` has an invalid port"))?;
+                (host, port)
+            }
+            _ if tls => (authority, 443),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("`{url}` does not have a host"));
+        }
+
+        Ok(Url {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// A copy of the URL with the given path
+    pub(crate) fn with_path(&self, path: impl Into<String>) -> Self {
+        Url {
+            tls: self.tls,
+            host: self.host.clone(),
+            port: self.port,
+            path: path.into(),
+        }
+    }
+}
+
+/// Send a request with the given body and return the response's status code.
+///
+/// The timeout applies to connecting, sending the request, and reading the response separately.
+pub(crate) fn send(
+    method: &str,
+    url: &Url,
+    content_type: &str,
+    body: &[u8],
+    timeout: Duration,
+) -> io::Result<u16> {
+    let host = url.host.trim_start_matches('[').trim_end_matches(']');
+    let addr = (host, url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the host has no addresses"))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    #[cfg(feature = "tls")]
+    if url.tls {
+        return exchange(tls_stream(host, stream)?, method, url, content_type, body);
+    }
+    exchange(stream, method, url, content_type, body)
+}
+
+/// Send the request over the connection and read the response's status code
+fn exchange(
+    mut stream: impl Read + Write,
+    method: &str,
+    url: &Url,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<u16> {
+    let path = if url.path.is_empty() { "/" } else { &url.path };
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        url.host,
+        url.port,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid HTTP response: {}", status_line.trim()),
+            )
+        })?;
+
+    // Read the rest of the response so the server does not see the connection being reset
+    let _ = io::copy(&mut reader.take(1024 * 1024), &mut io::sink());
+    Ok(status)
+}
+
+/// Wrap the connection in a TLS session that verifies the host's certificate
+#[cfg(feature = "tls")]
+fn tls_stream(
+    host: &str,
+    stream: TcpStream,
+) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    static CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("the default TLS protocol versions are supported by ring")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        Arc::new(config)
+    });
+
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let connection =
+        ClientConnection::new(CONFIG.clone(), server_name).map_err(io::Error::other)?;
+    Ok(StreamOwned::new(connection, stream))
+}
@@ -1,7 +1,56 @@
 use crate::constants::*;
+use crate::propagation::caller_service;
+use once_cell::sync::Lazy;
+use smallvec::SmallVec;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
+use std::sync::RwLock;
+
+/// A label key and value
+pub type Label = (&'static str, &'static str);
+
+/// The custom labels of a function, stored inline unless a function has more than a few of them
+pub(crate) type CustomLabels = SmallVec<[Label; 4]>;
+
+/// The maximum number of distinct values that will be recorded for each dynamic label key.
+const MAX_DYNAMIC_LABEL_VALUES: usize = 1000;
+
+static DYNAMIC_LABEL_VALUES: Lazy<RwLock<HashMap<&'static str, HashSet<&'static str>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Turn the value of a dynamic label into a `&'static str`.
+///
+/// Each distinct value is leaked exactly once, so the number of values per label key
+/// is capped to keep both the memory usage and the cardinality of the metrics bounded.
+/// Once the cap is reached, all new values are recorded as `__autometrics_other`.
+pub fn intern_label_value(key: &'static str, value: impl AsRef<str>) -> &'static str {
+    let value = value.as_ref();
+
+    if let Some(interned) = DYNAMIC_LABEL_VALUES
+        .read()
+        .expect("autometrics label values lock poisoned")
+        .get(key)
+        .and_then(|values| values.get(value).copied())
+    {
+        return interned;
+    }
 
-pub(crate) type Label = (&'static str, &'static str);
+    let mut dynamic_label_values = DYNAMIC_LABEL_VALUES
+        .write()
+        .expect("autometrics label values lock poisoned");
+    let values = dynamic_label_values.entry(key).or_default();
+    // Another thread may have inserted the value while we were waiting for the lock
+    if let Some(interned) = values.get(value).copied() {
+        return interned;
+    }
+    if values.len() >= MAX_DYNAMIC_LABEL_VALUES {
+        return OVERFLOW_LABEL_VALUE;
+    }
+
+    let interned: &'static str = Box::leak(value.to_string().into_boxed_str());
+    values.insert(interned);
+    interned
+}
 
 pub fn create_label_array(
     result: &'static str,
@@ -9,26 +58,26 @@ pub fn create_label_array(
     module: &'static str,
     caller: &'static str,
     return_value_type: Option<&'static str>,
+    custom_labels: &[Label],
 ) -> LabelArray {
-    let function_label = (FUNCTION_KEY, function);
-    let module_label = (MODULE_KEY, module);
-    let caller_label = (CALLER_KEY, caller);
-    let result_label = (RESULT_KEY, result);
+    let mut labels = LabelArray::with_capacity(6 + custom_labels.len());
+    labels.push((FUNCTION_KEY, function));
+    labels.push((MODULE_KEY, module));
+    labels.push((CALLER_KEY, caller));
+    // Functions called directly by a remote caller are labeled with the caller's service
+    if let Some(service) = caller_service(caller) {
+        labels.push((CALLER_SERVICE_KEY, service));
+    }
+    labels.push((RESULT_KEY, result));
 
     // Add another label for the return value if the type implements Into<&'static str>.
     // This is most likely useful for enums representing error (or potentially success) types.
     if let Some(value) = return_value_type {
-        let value_label = (result, value);
-        LabelArray::Five([
-            function_label,
-            module_label,
-            caller_label,
-            result_label,
-            value_label,
-        ])
-    } else {
-        LabelArray::Four([function_label, module_label, caller_label, result_label])
+        labels.push((result, value));
     }
+
+    labels.extend(custom_labels);
+    labels
 }
 
 // The following is a convoluted way to figure out if the return type resolves to a Result
@@ -49,6 +98,7 @@ pub trait GetLabelsFromResult {
         function: &'static str,
         module: &'static str,
         caller: &'static str,
+        custom_labels: &[Label],
     ) -> LabelArray;
 }
 
@@ -58,29 +108,49 @@ impl<T, E> GetLabelsFromResult for Result<T, E> {
         function: &'static str,
         module: &'static str,
         caller: &'static str,
+        custom_labels: &[Label],
     ) -> LabelArray {
         let (result, value_as_static_str) = match self {
             Ok(ok) => (OK_KEY, ok.__autometrics_static_str()),
             Err(err) => (ERROR_KEY, err.__autometrics_static_str()),
         };
 
-        create_label_array(result, function, module, caller, value_as_static_str)
+        create_label_array(
+            result,
+            function,
+            module,
+            caller,
+            value_as_static_str,
+            custom_labels,
+        )
     }
 }
 
-pub enum LabelArray {
-    Four([Label; 4]),
-    Five([Label; 5]),
+/// The labels attached to a single function call.
+///
+/// This contains the autometrics labels followed by any custom labels
+/// that were specified for the function.
+pub struct LabelArray(SmallVec<[Label; 8]>);
+
+impl LabelArray {
+    fn with_capacity(capacity: usize) -> Self {
+        LabelArray(SmallVec::with_capacity(capacity))
+    }
+
+    fn push(&mut self, label: Label) {
+        self.0.push(label);
+    }
+
+    fn extend(&mut self, labels: &[Label]) {
+        self.0.extend_from_slice(labels);
+    }
 }
 
 impl Deref for LabelArray {
     type Target = [Label];
 
     fn deref(&self) -> &Self::Target {
-        match self {
-            LabelArray::Four(l) => l,
-            LabelArray::Five(l) => l,
-        }
+        &self.0
     }
 }
 
@@ -89,9 +159,19 @@ pub trait GetLabels {
         &self,
         function: &'static str,
         module: &'static str,
-        _caller: &'static str,
-    ) -> [Label; 2] {
-        [(FUNCTION_KEY, function), (MODULE_KEY, module)]
+        caller: &'static str,
+        custom_labels: &[Label],
+    ) -> LabelArray {
+        let mut labels = LabelArray::with_capacity(4 + custom_labels.len());
+        labels.push((FUNCTION_KEY, function));
+        labels.push((MODULE_KEY, module));
+        // Functions that do not return a Result have no result label, but they still report their caller
+        labels.push((CALLER_KEY, caller));
+        if let Some(service) = caller_service(caller) {
+            labels.push((CALLER_SERVICE_KEY, service));
+        }
+        labels.extend(custom_labels);
+        labels
     }
 }
 
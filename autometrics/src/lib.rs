@@ -7,18 +7,67 @@
 
 #[cfg(feature = "alerts")]
 mod alerts;
+mod buckets;
+mod caller;
 mod constants;
+#[cfg(all(
+    feature = "exemplars",
+    any(
+        feature = "native",
+        all(feature = "opentelemetry", feature = "prometheus-exporter")
+    )
+))]
+mod exemplars;
+#[cfg(any(feature = "otlp-exporter", feature = "pushgateway"))]
+mod http_client;
 mod labels;
+#[cfg(feature = "metrics-server")]
+mod metrics_server;
+#[cfg(feature = "otlp-exporter")]
+mod otlp_exporter;
 #[cfg(feature = "prometheus-exporter")]
 mod prometheus_exporter;
+mod propagation;
+#[cfg(any(
+    feature = "native",
+    feature = "prometheus-exporter",
+    feature = "otlp-exporter"
+))]
+mod protobuf;
+#[cfg(feature = "pushgateway")]
+mod pushgateway;
+#[cfg(feature = "tracing")]
+mod spans;
 mod task_local;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(any(feature = "native", feature = "prometheus-exporter"))]
+mod text;
+#[cfg(feature = "tower")]
+pub mod tower;
+#[cfg(feature = "tracing-subscriber")]
+pub mod tracing_subscriber;
 mod tracker;
 
+pub use self::caller::*;
+pub use self::labels::Label;
+pub use self::propagation::*;
+#[cfg(feature = "opentelemetry")]
+pub use self::tracker::set_meter_provider;
+#[cfg(feature = "native")]
+pub use self::tracker::{encode_native_metrics, encode_native_metrics_protobuf, TextFormat};
+pub use self::tracker::{reset_global_tracker, set_global_tracker, FunctionCall, Tracker};
 pub use autometrics_macros::autometrics;
 
 // Optional exports
+#[cfg(feature = "metrics-server")]
+pub use self::metrics_server::*;
+#[cfg(feature = "otlp-exporter")]
+pub use self::otlp_exporter::*;
 #[cfg(feature = "prometheus-exporter")]
 pub use self::prometheus_exporter::*;
+#[cfg(feature = "pushgateway")]
+pub use self::pushgateway::*;
 #[cfg(feature = "alerts")]
 pub use crate::alerts::*;
 
@@ -37,9 +86,15 @@ pub mod __private {
 
     #[cfg(feature = "alerts")]
     pub use crate::alerts::{Alert, METRICS};
+    pub use crate::buckets::*;
     pub use crate::labels::*;
-    pub use crate::tracker::{AutometricsTracker, TrackMetrics};
+    #[cfg(feature = "tracing")]
+    pub use crate::spans::*;
+    pub use crate::tracker::{AutometricsTracker, CallSite, TrackMetrics};
     pub use const_format::str_replace;
+    pub use linkme;
+    #[cfg(feature = "tracing")]
+    pub use tracing;
 
     /// Task-local value used for tracking which function called the current function
     pub static CALLER: LocalKey<&'static str> = {
@@ -0,0 +1,259 @@
+use crate::{encode_global_metrics_as, Format};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Scrapers send the whole request right away, so connections that have not sent
+/// the request head within this time are closed, no matter how slowly they send it
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(1);
+/// The time a client has to receive the response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum size of the request head we are willing to read
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// The number of connections that are handled at the same time.
+/// The metrics are usually only scraped by one or two Prometheus servers
+const WORKERS: usize = 4;
+/// The number of connections that can wait for a worker. Any connections beyond that are closed right away
+const QUEUE_SIZE: usize = 16;
+
+/// Start a minimal HTTP server in a background thread that serves the
+/// metrics from [`encode_global_metrics`](crate::encode_global_metrics) on the `/metrics` path.
+///
+/// The metrics are served in the [`Format`] that the client prefers, based on its `Accept` header.
+///
+/// This is useful for applications, such as worker processes, that do not
+/// already run an HTTP server that the metrics route could be added to.
+///
+/// ```rust,no_run
+/// # fn main() -> std::io::Result<()> {
+/// let server = autometrics::serve_metrics("0.0.0.0:9464")?;
+/// // ...
+/// server.shutdown();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The server keeps running in the background until [`MetricsServerHandle::shutdown`] is called,
+/// even if the handle is dropped.
+pub fn serve_metrics(addr: impl ToSocketAddrs) -> io::Result<MetricsServerHandle> {
+    serve_metrics_on_path(addr, "/metrics")
+}
+
+/// Like [`serve_metrics`], but serves the metrics on the given path instead of `/metrics`.
+pub fn serve_metrics_on_path(
+    addr: impl ToSocketAddrs,
+    path: impl Into<String>,
+) -> io::Result<MetricsServerHandle> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let path: Arc<str> = path.into().into();
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    // The connections are handled by a fixed number of workers, so that a slow client does not hold up
+    // other scrapes or the shutdown, without starting a thread for every connection.
+    // The workers stop once the listener thread stops and the queue is empty
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(QUEUE_SIZE);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let receiver = receiver.clone();
+        let path = path.clone();
+        thread::Builder::new()
+            .name("autometrics-metrics-connection".to_string())
+            .spawn(move || loop {
+                let next = receiver
+                    .lock()
+                    .expect("autometrics metrics server queue lock poisoned")
+                    .recv();
+                match next {
+                    Ok(stream) => {
+                        let _ = handle_connection(stream, &path);
+                    }
+                    Err(_) => break,
+                }
+            })?;
+    }
+
+    let thread = {
+        let shutdown = shutdown.clone();
+        thread::Builder::new()
+            .name("autometrics-metrics-server".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::Acquire) {
+                        break;
+                    }
+                    // Errors are specific to a single connection, so we keep serving.
+                    // If all of the workers are busy and the queue is full, the connection is dropped
+                    if let Ok(stream) = stream {
+                        let _ = sender.try_send(stream);
+                    }
+                }
+            })?
+    };
+
+    Ok(MetricsServerHandle {
+        local_addr,
+        shutdown,
+        thread,
+    })
+}
+
+/// Handle to the server started by [`serve_metrics`]
+pub struct MetricsServerHandle {
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl MetricsServerHandle {
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting new connections and wait for the server thread to stop.
+    ///
+    /// Requests that are already being handled finish in the background.
+    pub fn shutdown(self) {
+        self.shutdown.store(true, Ordering::Release);
+
+        // Wake up the listener, which is blocked waiting for the next connection
+        let mut wake_addr = self.local_addr;
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(match wake_addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let _ = TcpStream::connect(wake_addr);
+
+        let _ = self.thread.join();
+    }
+}
+
+fn handle_connection(stream: TcpStream, path: &str) -> io::Result<()> {
+    let mut reader =
+        BufReader::new(Deadline::new(&stream, REQUEST_HEAD_TIMEOUT)).take(MAX_REQUEST_SIZE as u64);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let request_path = parts.next().unwrap_or_default();
+
+    let mut accept = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("accept") {
+                accept = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    // Ignore the query string, if there is one
+    let request_path = request_path.split('?').next().unwrap_or_default();
+    let response = if request_path != path {
+        Response::text("404 Not Found", "Not Found\n")
+    } else if method != "GET" && method != "HEAD" {
+        Response::text("405 Method Not Allowed", "Method Not Allowed\n")
+    } else {
+        let format = match accept.as_deref() {
+            Some(accept) => Format::from_accept_header(accept),
+            None => Some(Format::Text),
+        };
+        match format {
+            Some(format) => match encode_global_metrics_as(format) {
+                Ok(metrics) => Response {
+                    status: "200 OK",
+                    content_type: format.content_type(),
+                    body: metrics,
+                },
+                Err(err) => Response::text("500 Internal Server Error", format!("{err:?}\n")),
+            },
+            None => Response::text("406 Not Acceptable", "Not Acceptable\n"),
+        }
+    };
+
+    response.write_to(Deadline::new(&stream, RESPONSE_TIMEOUT), method == "HEAD")
+}
+
+/// A connection that times out once the deadline has passed, rather than only
+/// when a single read or write takes longer than the timeout
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    fn new(stream: &'a TcpStream, timeout: Duration) -> Self {
+        Deadline {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn remaining(&self) -> io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining),
+            _ => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the connection timed out",
+            )),
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    fn write_to(self, mut stream: impl Write, head_only: bool) -> io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        )?;
+        if !head_only {
+            stream.write_all(&self.body)?;
+        }
+        stream.flush()
+    }
+}
@@ -0,0 +1,372 @@
+use crate::buckets::{
+    all_histogram_buckets, are_valid_buckets, set_default_histogram_buckets,
+    DEFAULT_HISTOGRAM_BUCKETS,
+};
+use crate::http_client::{self, Url};
+use once_cell::sync::OnceCell;
+use opentelemetry_api::{global, metrics::MetricsError, Context, KeyValue};
+use opentelemetry_sdk::{
+    export::metrics::aggregation::{self, AggregationKind, TemporalitySelector},
+    metrics::{
+        controllers::{self, BasicController},
+        processors,
+        sdk_api::Descriptor,
+        selectors,
+    },
+    Resource,
+};
+use std::fmt;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+mod encode;
+
+static GLOBAL_OTLP_EXPORTER: OnceCell<OtlpExporter> = OnceCell::new();
+
+const METRICS_PATH: &str = "/v1/metrics";
+const CONTENT_TYPE: &str = "application/x-protobuf";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether each push contains the totals since the process started or only the changes since the last push
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Temporality {
+    /// Push the totals since the process started, which is what Prometheus expects
+    #[default]
+    Cumulative,
+    /// Push the changes since the previous push, which some backends (and short-lived processes) prefer
+    Delta,
+}
+
+/// Makes the SDK's processor keep the metrics in the configured temporality
+#[derive(Clone, Copy)]
+struct TemporalitySelection(Temporality);
+
+impl TemporalitySelector for TemporalitySelection {
+    fn temporality_for(
+        &self,
+        _descriptor: &Descriptor,
+        _kind: &AggregationKind,
+    ) -> aggregation::Temporality {
+        match self.0 {
+            Temporality::Cumulative => aggregation::Temporality::Cumulative,
+            Temporality::Delta => aggregation::Temporality::Delta,
+        }
+    }
+}
+
+/// Builder for an exporter that periodically pushes the metrics recorded with
+/// OpenTelemetry to an OTLP receiver, such as the OpenTelemetry Collector, over HTTP.
+///
+/// Use this instead of scraping for processes that cannot be scraped, such as short jobs
+/// and serverless functions.
+///
+/// `https://` endpoints are supported with the `tls` feature, so functions without a collector
+/// sidecar can push to a remote receiver directly. Without it, an `https://` endpoint is rejected
+/// with [`OtlpExporterError::InvalidEndpoint`].
+///
+/// The exporter replaces the global OpenTelemetry meter provider, and so does the
+/// [`PrometheusExporterBuilder`](crate::PrometheusExporterBuilder). Only the one that is
+/// initialized last receives the metrics produced with the `opentelemetry` feature, so once the
+/// OTLP exporter is installed, the OpenTelemetry metrics no longer appear in the Prometheus exporter.
+/// Do not use the two together unless the metrics are recorded with another library,
+/// like `prometheus` or `metrics`.
+///
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # fn main() -> Result<(), autometrics::OtlpExporterError> {
+/// let exporter = autometrics::OtlpExporterBuilder::new("http://localhost:4318")
+///     .interval(Duration::from_secs(10))
+///     .temporality(autometrics::Temporality::Delta)
+///     .resource_attribute("service.name", "api")
+///     .init()?;
+/// // ...
+/// exporter.shutdown()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct OtlpExporterBuilder {
+    endpoint: String,
+    interval: Duration,
+    timeout: Duration,
+    temporality: Temporality,
+    buckets: Vec<f64>,
+    resource_attributes: Vec<KeyValue>,
+}
+
+impl OtlpExporterBuilder {
+    /// Push the metrics to the OTLP/HTTP receiver at the given `http://` URL,
+    /// or `https://` URL if the `tls` feature is enabled.
+    ///
+    /// The metrics are sent to the `/v1/metrics` path of the endpoint,
+    /// unless the endpoint's path already ends with it.
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        OtlpExporterBuilder {
+            endpoint: endpoint.into(),
+            interval: DEFAULT_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+            temporality: Temporality::default(),
+            buckets: DEFAULT_HISTOGRAM_BUCKETS.to_vec(),
+            resource_attributes: Vec::new(),
+        }
+    }
+
+    /// How often to push the metrics (defaults to 60 seconds)
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long to wait for the receiver when pushing the metrics (defaults to 10 seconds)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether to push cumulative or delta values (defaults to [`Temporality::Cumulative`])
+    pub fn temporality(mut self, temporality: Temporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    /// Set the default histogram buckets (in seconds) used to track function latencies.
+    ///
+    /// These work the same as the buckets of the
+    /// [`PrometheusExporterBuilder`](crate::PrometheusExporterBuilder::buckets).
+    pub fn buckets(mut self, buckets: impl Into<Vec<f64>>) -> Self {
+        self.buckets = buckets.into();
+        self
+    }
+
+    /// Add an attribute to the resource that the metrics are pushed for, such as `service.name`.
+    ///
+    /// The resource also includes the attributes from the `OTEL_SERVICE_NAME` and
+    /// `OTEL_RESOURCE_ATTRIBUTES` environment variables.
+    pub fn resource_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.resource_attributes
+            .push(KeyValue::new(key.into(), value.into()));
+        self
+    }
+
+    /// Build the exporter, set it as the global OpenTelemetry meter provider,
+    /// and start pushing the metrics in a background thread.
+    ///
+    /// This replaces the meter provider set by the Prometheus exporter, if it was initialized first,
+    /// so the metrics recorded with OpenTelemetry stop appearing in the Prometheus exporter.
+    /// This returns an error if the endpoint is not an `http://` URL (or `https://` URL with the `tls` feature),
+    /// or if the OTLP exporter was already initialized.
+    pub fn init(self) -> Result<OtlpExporter, OtlpExporterError> {
+        let url = Url::parse(&self.endpoint).map_err(OtlpExporterError::InvalidEndpoint)?;
+        if !are_valid_buckets(&self.buckets) {
+            return Err(OtlpExporterError::InvalidBuckets);
+        }
+
+        let mut initialized = false;
+        let exporter = GLOBAL_OTLP_EXPORTER.get_or_try_init(|| {
+            initialized = true;
+            self.build(url)
+        })?;
+
+        if initialized {
+            Ok(exporter.clone())
+        } else {
+            Err(OtlpExporterError::AlreadyInitialized)
+        }
+    }
+
+    fn build(self, url: Url) -> Result<OtlpExporter, OtlpExporterError> {
+        let url = if url.path().trim_end_matches('/').ends_with(METRICS_PATH) {
+            url
+        } else {
+            let path = format!("{}{METRICS_PATH}", url.path().trim_end_matches('/'));
+            url.with_path(path)
+        };
+
+        // The buckets can only be configured per metric, so this uses the default buckets
+        // combined with the buckets configured for individual functions
+        let histogram_buckets = all_histogram_buckets(&self.buckets);
+        set_default_histogram_buckets(self.buckets);
+
+        let temporality = TemporalitySelection(self.temporality);
+        let controller = controllers::basic(
+            processors::factory(selectors::simple::histogram(histogram_buckets), temporality)
+                // Cumulative values need to be kept between pushes
+                .with_memory(self.temporality == Temporality::Cumulative),
+        )
+        .build();
+        crate::set_meter_provider(controller.clone());
+
+        let exporter = OtlpExporter(Arc::new(Inner {
+            controller,
+            temporality,
+            url,
+            timeout: self.timeout,
+            resource: Resource::default().merge(&Resource::new(self.resource_attributes)),
+            push_lock: Mutex::new(()),
+            worker: Mutex::new(None),
+        }));
+
+        let (stop, stopped) = mpsc::channel();
+        let thread = {
+            let exporter = exporter.clone();
+            let interval = self.interval;
+            thread::Builder::new()
+                .name("autometrics-otlp-exporter".to_string())
+                .spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                        // There is no caller to return the error to, so it is reported the same way
+                        // as the errors of the OpenTelemetry SDK's own background tasks
+                        if let Err(err) = exporter.flush() {
+                            global::handle_error(MetricsError::Other(err.to_string()));
+                        }
+                    }
+                })?
+        };
+        *exporter
+            .0
+            .worker
+            .lock()
+            .expect("OTLP exporter lock poisoned") = Some(Worker { stop, thread });
+
+        Ok(exporter)
+    }
+}
+
+/// Initialize an exporter that pushes the metrics to the OTLP/HTTP receiver
+/// at the given endpoint, such as `http://localhost:4318`, at the given interval.
+///
+/// This uses the default settings of the [`OtlpExporterBuilder`] otherwise, and like it,
+/// replaces the global OpenTelemetry meter provider set by the Prometheus exporter.
+/// Call [`OtlpExporter::shutdown`] before the process exits so the last metrics are pushed as well.
+///
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # fn main() -> Result<(), autometrics::OtlpExporterError> {
+/// let exporter = autometrics::init_otlp("http://localhost:4318", Duration::from_secs(10))?;
+/// // ...
+/// exporter.shutdown()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn init_otlp(
+    endpoint: impl Into<String>,
+    interval: Duration,
+) -> Result<OtlpExporter, OtlpExporterError> {
+    OtlpExporterBuilder::new(endpoint).interval(interval).init()
+}
+
+/// Handle to the exporter started by [`init_otlp`] or the [`OtlpExporterBuilder`]
+#[derive(Clone)]
+pub struct OtlpExporter(Arc<Inner>);
+
+struct Inner {
+    controller: BasicController,
+    temporality: TemporalitySelection,
+    url: Url,
+    timeout: Duration,
+    resource: Resource,
+    /// Pushes are not run concurrently, so that delta values are not sent twice
+    push_lock: Mutex<()>,
+    worker: Mutex<Option<Worker>>,
+}
+
+struct Worker {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl OtlpExporter {
+    /// Push the current metrics now, without waiting for the next interval
+    pub fn flush(&self) -> Result<(), OtlpExporterError> {
+        let inner = &self.0;
+        let _guard = inner.push_lock.lock().expect("OTLP exporter lock poisoned");
+
+        inner.controller.collect(&Context::current())?;
+        let request = encode::encode(&inner.controller, inner.temporality, &inner.resource)?;
+
+        let status = http_client::send("POST", &inner.url, CONTENT_TYPE, &request, inner.timeout)?;
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(OtlpExporterError::UnexpectedStatus(status))
+        }
+    }
+
+    /// Stop pushing the metrics periodically and push them one last time.
+    ///
+    /// Call this before the process exits so that the metrics recorded since
+    /// the last push are not lost.
+    pub fn shutdown(&self) -> Result<(), OtlpExporterError> {
+        let worker = self
+            .0
+            .worker
+            .lock()
+            .expect("OTLP exporter lock poisoned")
+            .take();
+        if let Some(worker) = worker {
+            let _ = worker.stop.send(());
+            let _ = worker.thread.join();
+        }
+        self.flush()
+    }
+}
+
+/// An error that occurred while initializing the OTLP exporter or pushing the metrics
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OtlpExporterError {
+    /// The global OTLP exporter was already initialized
+    AlreadyInitialized,
+    /// The histogram buckets must be finite numbers in increasing order
+    InvalidBuckets,
+    /// The endpoint is not a valid `http://` URL, or an `https://` URL without the `tls` feature
+    InvalidEndpoint(String),
+    /// The metrics could not be collected from the OpenTelemetry SDK
+    OpenTelemetry(MetricsError),
+    /// The background thread could not be started, or the metrics could not be sent
+    Io(io::Error),
+    /// The receiver responded with a status code other than 2xx
+    UnexpectedStatus(u16),
+}
+
+impl fmt::Display for OtlpExporterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyInitialized => f.write_str("the OTLP exporter was already initialized"),
+            Self::InvalidBuckets => {
+                f.write_str("histogram buckets must be finite numbers in increasing order")
+            }
+            Self::InvalidEndpoint(err) => write!(f, "invalid OTLP endpoint: {err}"),
+            Self::OpenTelemetry(err) => write!(f, "failed to collect the metrics: {err}"),
+            Self::Io(err) => write!(f, "failed to push the metrics: {err}"),
+            Self::UnexpectedStatus(status) => {
+                write!(f, "the OTLP receiver responded with status {status}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OtlpExporterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::OpenTelemetry(err) => Some(err),
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<MetricsError> for OtlpExporterError {
+    fn from(err: MetricsError) -> Self {
+        Self::OpenTelemetry(err)
+    }
+}
+
+impl From<io::Error> for OtlpExporterError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
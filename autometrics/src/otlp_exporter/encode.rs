@@ -0,0 +1,240 @@
+//! Encodes the metrics collected by the OpenTelemetry SDK as an OTLP `ExportMetricsServiceRequest`.
+//!
+//! This uses the same minimal protobuf encoder as the Prometheus exporter, so that pushing the metrics
+//! does not need the OTLP exporter crate with its HTTP client and async runtime. See
+//! https://github.com/open-telemetry/opentelemetry-proto/blob/main/opentelemetry/proto/metrics/v1/metrics.proto
+
+use super::{Temporality, TemporalitySelection};
+use crate::protobuf::*;
+use opentelemetry_api::{metrics::Result, Key, Value};
+use opentelemetry_sdk::{
+    export::metrics::{
+        aggregation::{Count as _, Histogram as _, LastValue as _, Sum as _},
+        InstrumentationLibraryReader, Record,
+    },
+    metrics::{
+        aggregators::{HistogramAggregator, LastValueAggregator, SumAggregator},
+        sdk_api::{InstrumentKind, Number, NumberKind},
+    },
+    Resource,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// AggregationTemporality
+const TEMPORALITY_DELTA: u64 = 1;
+const TEMPORALITY_CUMULATIVE: u64 = 2;
+
+/// The data of one metric, with the encoded data points of every record with its name
+struct MetricData {
+    name: String,
+    description: String,
+    unit: String,
+    kind: DataKind,
+    data_points: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum DataKind {
+    Gauge,
+    Sum { is_monotonic: bool },
+    Histogram,
+}
+
+/// Encode every record in the latest collection of the controller, grouped by instrumentation library
+pub(super) fn encode(
+    reader: &dyn InstrumentationLibraryReader,
+    temporality: TemporalitySelection,
+    resource: &Resource,
+) -> Result<Vec<u8>> {
+    let mut resource_metrics = Vec::new();
+    write_message(&mut resource_metrics, 1, &encode_resource(resource));
+
+    reader.try_for_each(&mut |library, records| {
+        let mut metrics: Vec<MetricData> = Vec::new();
+        records.try_for_each(&temporality, &mut |record| add_record(&mut metrics, record))?;
+        if metrics.is_empty() {
+            return Ok(());
+        }
+
+        let mut scope = Vec::new();
+        write_bytes(&mut scope, 1, library.name.as_bytes());
+        if let Some(version) = &library.version {
+            write_bytes(&mut scope, 2, version.as_bytes());
+        }
+        let mut scope_metrics = Vec::new();
+        write_message(&mut scope_metrics, 1, &scope);
+        for metric in &metrics {
+            write_message(&mut scope_metrics, 2, &encode_metric(metric, temporality.0));
+        }
+        write_message(&mut resource_metrics, 2, &scope_metrics);
+        Ok(())
+    })?;
+
+    let mut request = Vec::new();
+    write_message(&mut request, 1, &resource_metrics);
+    Ok(request)
+}
+
+fn encode_resource(resource: &Resource) -> Vec<u8> {
+    let mut message = Vec::new();
+    for (key, value) in resource.iter() {
+        write_attribute(&mut message, 1, key, value);
+    }
+    message
+}
+
+fn add_record(metrics: &mut Vec<MetricData>, record: &Record) -> Result<()> {
+    let aggregator = match record.aggregator() {
+        Some(aggregator) => aggregator,
+        None => return Ok(()),
+    };
+    let descriptor = record.descriptor();
+    let number_kind = descriptor.number_kind();
+    let start = unix_nanos(record.start_time());
+    let end = unix_nanos(record.end_time());
+
+    let mut point = Vec::new();
+    let kind = if let Some(histogram) = aggregator.as_any().downcast_ref::<HistogramAggregator>() {
+        let buckets = histogram.histogram()?;
+        write_attributes(&mut point, 9, record);
+        write_fixed64(&mut point, 2, start);
+        write_fixed64(&mut point, 3, end);
+        write_fixed64(&mut point, 4, histogram.count()?);
+        write_double(&mut point, 5, histogram.sum()?.to_f64(number_kind));
+        // The last count is for the values above the highest boundary, like the +Inf bucket
+        let counts: Vec<u8> = buckets
+            .counts()
+            .iter()
+            .flat_map(|count| (*count as u64).to_le_bytes())
+            .collect();
+        write_bytes(&mut point, 6, &counts);
+        let bounds: Vec<u8> = buckets
+            .boundaries()
+            .iter()
+            .flat_map(|bound| bound.to_le_bytes())
+            .collect();
+        write_bytes(&mut point, 7, &bounds);
+        DataKind::Histogram
+    } else if let Some(sum) = aggregator.as_any().downcast_ref::<SumAggregator>() {
+        write_number_point(&mut point, record, start, end, sum.sum()?, number_kind);
+        DataKind::Sum {
+            is_monotonic: matches!(
+                descriptor.instrument_kind(),
+                InstrumentKind::Counter | InstrumentKind::CounterObserver
+            ),
+        }
+    } else if let Some(last_value) = aggregator.as_any().downcast_ref::<LastValueAggregator>() {
+        let (value, timestamp) = last_value.last_value()?;
+        write_number_point(
+            &mut point,
+            record,
+            start,
+            unix_nanos(&timestamp),
+            value,
+            number_kind,
+        );
+        DataKind::Gauge
+    } else {
+        // Other aggregations are not used with the histogram selector
+        return Ok(());
+    };
+
+    let index = match metrics
+        .iter()
+        .position(|metric| metric.name == descriptor.name())
+    {
+        Some(index) => index,
+        None => {
+            metrics.push(MetricData {
+                name: descriptor.name().to_string(),
+                description: descriptor
+                    .description()
+                    .map(|description| description.to_string())
+                    .unwrap_or_default(),
+                unit: descriptor
+                    .unit()
+                    .map(|unit| unit.to_string())
+                    .unwrap_or_default(),
+                kind,
+                data_points: Vec::new(),
+            });
+            metrics.len() - 1
+        }
+    };
+    write_message(&mut metrics[index].data_points, 1, &point);
+    Ok(())
+}
+
+fn write_number_point(
+    output: &mut Vec<u8>,
+    record: &Record,
+    start: u64,
+    end: u64,
+    value: Number,
+    number_kind: &NumberKind,
+) {
+    write_attributes(output, 7, record);
+    write_fixed64(output, 2, start);
+    write_fixed64(output, 3, end);
+    match number_kind {
+        NumberKind::F64 => write_double(output, 4, value.to_f64(number_kind)),
+        // sfixed64 values are encoded as the two's complement
+        _ => write_fixed64(output, 6, value.to_i64(number_kind) as u64),
+    }
+}
+
+fn encode_metric(metric: &MetricData, temporality: Temporality) -> Vec<u8> {
+    let temporality = match temporality {
+        Temporality::Cumulative => TEMPORALITY_CUMULATIVE,
+        Temporality::Delta => TEMPORALITY_DELTA,
+    };
+
+    let mut message = Vec::new();
+    write_bytes(&mut message, 1, metric.name.as_bytes());
+    write_bytes(&mut message, 2, metric.description.as_bytes());
+    write_bytes(&mut message, 3, metric.unit.as_bytes());
+
+    let mut data = metric.data_points.clone();
+    match metric.kind {
+        DataKind::Gauge => write_message(&mut message, 5, &data),
+        DataKind::Sum { is_monotonic } => {
+            write_varint_field(&mut data, 2, temporality);
+            write_varint_field(&mut data, 3, is_monotonic.into());
+            write_message(&mut message, 7, &data);
+        }
+        DataKind::Histogram => {
+            write_varint_field(&mut data, 2, temporality);
+            write_message(&mut message, 9, &data);
+        }
+    }
+    message
+}
+
+fn write_attributes(output: &mut Vec<u8>, field: u64, record: &Record) {
+    for (key, value) in record.attributes().iter() {
+        write_attribute(output, field, key, value);
+    }
+}
+
+/// Write a `KeyValue` message with the value as an `AnyValue`
+fn write_attribute(output: &mut Vec<u8>, field: u64, key: &Key, value: &Value) {
+    let mut any_value = Vec::new();
+    match value {
+        Value::Bool(value) => write_varint_field(&mut any_value, 2, (*value).into()),
+        // int64 values are encoded as varints of their two's complement
+        Value::I64(value) => write_varint_field(&mut any_value, 3, *value as u64),
+        Value::F64(value) => write_double(&mut any_value, 4, *value),
+        // Arrays are written as strings
+        _ => write_bytes(&mut any_value, 1, value.as_str().as_bytes()),
+    }
+
+    let mut key_value = Vec::new();
+    write_bytes(&mut key_value, 1, key.as_str().as_bytes());
+    write_message(&mut key_value, 2, &any_value);
+    write_message(output, field, &key_value);
+}
+
+fn unix_nanos(time: &SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
@@ -1,39 +1,465 @@
+use crate::buckets::{
+    all_histogram_buckets, are_valid_buckets, set_default_histogram_buckets,
+    DEFAULT_HISTOGRAM_BUCKETS,
+};
 #[cfg(feature = "metrics")]
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use once_cell::sync::Lazy;
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::OnceCell;
+use opentelemetry_api::metrics::MetricsError;
 use opentelemetry_prometheus::{exporter, PrometheusExporter};
-use opentelemetry_sdk::export::metrics::aggregation;
-use opentelemetry_sdk::metrics::{controllers, processors, selectors};
-use prometheus::{default_registry, Error, TextEncoder};
+use opentelemetry_sdk::{
+    export::metrics::aggregation,
+    metrics::{controllers, processors, selectors},
+};
+use prometheus::proto::{LabelPair, MetricFamily};
+use prometheus::{default_registry, Error, Registry, TextEncoder};
+use std::fmt;
 
-const HISTOGRAM_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.075, 0.1, 0.15, 0.2, 0.35, 0.5, 1.0];
-static GLOBAL_EXPORTER: Lazy<GlobalPrometheus> = Lazy::new(|| initialize_metrics_exporter());
+mod merge;
+mod openmetrics;
+#[cfg(feature = "metrics")]
+mod parse;
+mod protobuf;
+
+static GLOBAL_EXPORTER: OnceCell<GlobalPrometheus> = OnceCell::new();
 
 #[derive(Clone)]
 #[doc(hidden)]
 pub struct GlobalPrometheus {
-    exporter: PrometheusExporter,
+    registry: Registry,
+    /// The metrics recorded with OpenTelemetry are kept separately, so that the autometrics metrics
+    /// recorded by both OpenTelemetry and the prometheus crate are only exported once
+    opentelemetry_registry: Registry,
+    _exporter: PrometheusExporter,
+    const_labels: Vec<(String, String)>,
     #[cfg(feature = "metrics")]
-    handle: PrometheusHandle,
+    handle: Option<PrometheusHandle>,
 }
 
 impl GlobalPrometheus {
+    /// Gather the metric families from the registry, the `metrics` crate's recorder, and OpenTelemetry,
+    /// merged so that each family appears only once.
+    ///
+    /// The time series recorded by more than one library are taken from the first of them,
+    /// in that order. If the `native` feature is enabled, the autometrics metrics are left out,
+    /// because they are encoded from the native metrics instead.
+    fn gather(&self) -> Result<Vec<MetricFamily>, Error> {
+        let mut metric_families = self.registry.gather();
+        add_const_labels(&mut metric_families, &self.const_labels);
+
+        // The recorder only renders the text format, so it is parsed to be merged with the other metrics.
+        // It adds the constant labels itself
+        #[cfg(feature = "metrics")]
+        if let Some(handle) = &self.handle {
+            metric_families.extend(parse::parse(&handle.render())?);
+        }
+
+        let mut opentelemetry_families = self.opentelemetry_registry.gather();
+        add_const_labels(&mut opentelemetry_families, &self.const_labels);
+        metric_families.extend(opentelemetry_families);
+
+        #[cfg(feature = "native")]
+        metric_families
+            .retain(|family| !crate::tracker::native::is_autometrics_metric(family.get_name()));
+
+        merge::merge(metric_families)
+    }
+
     fn encode_metrics(&self) -> Result<String, Error> {
-        let metric_families = self.exporter.registry().gather();
         let encoder = TextEncoder::new();
         #[allow(unused_mut)]
-        let mut output = encoder.encode_to_string(&metric_families)?;
+        let mut output = encoder.encode_to_string(&self.gather()?)?;
 
-        #[cfg(feature = "metrics")]
-        {
-            output.push('\n');
-            output.push_str(&self.handle.render());
+        #[cfg(feature = "native")]
+        crate::tracker::native::encode(
+            &mut output,
+            crate::TextFormat::Prometheus,
+            &self.const_labels,
+        );
+
+        Ok(output)
+    }
+
+    fn encode_as(&self, format: Format) -> Result<Vec<u8>, Error> {
+        match format {
+            Format::Text => self.encode_metrics().map(String::into_bytes),
+            Format::OpenMetrics => self.encode_openmetrics().map(String::into_bytes),
+            Format::Protobuf => self.encode_protobuf(),
         }
+    }
+
+    fn encode_openmetrics(&self) -> Result<String, Error> {
+        let mut output = String::new();
+        openmetrics::encode(&mut output, &self.gather()?);
+
+        #[cfg(feature = "native")]
+        crate::tracker::native::encode(
+            &mut output,
+            crate::TextFormat::OpenMetrics,
+            &self.const_labels,
+        );
+
+        output.push_str("# EOF\n");
+        Ok(output)
+    }
+
+    fn encode_protobuf(&self) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        protobuf::encode(&mut output, &self.gather()?);
+
+        #[cfg(feature = "native")]
+        crate::tracker::native::protobuf::encode(&mut output, &self.const_labels);
 
         Ok(output)
     }
 }
 
+/// The formats that [`encode_global_metrics_as`] can encode the metrics in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Format {
+    /// The Prometheus text exposition format, version 0.0.4
+    #[default]
+    Text,
+    /// The [OpenMetrics](https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md)
+    /// text format, which includes exemplars if the `exemplars` feature is enabled
+    OpenMetrics,
+    /// The Prometheus protobuf format, which includes native histograms if the `native-histograms` feature
+    /// is enabled, as well as the exemplars of the `native` metrics
+    Protobuf,
+}
+
+impl Format {
+    /// The formats in order of preference when the client accepts them equally
+    const ALL: [Format; 3] = [Format::OpenMetrics, Format::Protobuf, Format::Text];
+
+    /// The value of the `Content-Type` header for metrics encoded in this format
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Format::Protobuf => "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited",
+        }
+    }
+
+    /// Pick the format that the client prefers, based on the value of its `Accept` header.
+    /// If the client accepts more than one format equally, the first of
+    /// [`OpenMetrics`](Format::OpenMetrics), [`Protobuf`](Format::Protobuf), and [`Text`](Format::Text) is used.
+    ///
+    /// Returns `None` if the client does not accept any of the formats.
+    /// If the request does not have an `Accept` header, use [`Format::Text`].
+    ///
+    /// ```rust
+    /// use autometrics::Format;
+    ///
+    /// // The header sent by Prometheus
+    /// let accept = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+    /// assert_eq!(Format::from_accept_header(accept), Some(Format::OpenMetrics));
+    /// assert_eq!(Format::from_accept_header("application/json"), None);
+    /// ```
+    pub fn from_accept_header(accept: &str) -> Option<Format> {
+        let mut preferred: Option<(Format, f32)> = None;
+        for media_range in accept.split(',') {
+            let mut params = media_range.split(';');
+            let format = match params.next().unwrap_or_default().trim() {
+                "application/openmetrics-text" => Format::OpenMetrics,
+                "application/vnd.google.protobuf" => Format::Protobuf,
+                // Wildcards are served the Prometheus text format
+                "text/plain" | "text/*" | "*/*" => Format::Text,
+                _ => continue,
+            };
+            // Media ranges with a quality value of 0 are explicitly not acceptable
+            let quality = params
+                .find_map(|param| match param.trim().split_once('=') {
+                    Some(("q", quality)) => quality.trim().parse::<f32>().ok(),
+                    _ => None,
+                })
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+
+            let is_preferred = match preferred {
+                Some((preferred, preferred_quality)) => {
+                    quality > preferred_quality
+                        || (quality == preferred_quality && rank(format) < rank(preferred))
+                }
+                None => true,
+            };
+            if is_preferred {
+                preferred = Some((format, quality));
+            }
+        }
+        preferred.map(|(format, _)| format)
+    }
+}
+
+fn rank(format: Format) -> usize {
+    Format::ALL
+        .iter()
+        .position(|other| *other == format)
+        .unwrap_or(Format::ALL.len())
+}
+
+/// Attach the constant labels to every metric gathered from the registry
+fn add_const_labels(metric_families: &mut [MetricFamily], const_labels: &[(String, String)]) {
+    if const_labels.is_empty() {
+        return;
+    }
+
+    for family in metric_families {
+        for metric in family.mut_metric().iter_mut() {
+            for (name, value) in const_labels {
+                let mut label = LabelPair::new();
+                label.set_name(name.clone());
+                label.set_value(value.clone());
+                metric.mut_label().push(label);
+            }
+            // The Prometheus text format expects the labels to be sorted by name
+            metric
+                .mut_label()
+                .sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        }
+    }
+}
+
+/// Builder for the Prometheus metrics collector and exporter.
+///
+/// Use this instead of [`global_metrics_exporter`] if you need to customize
+/// the exporter, for example to use different histogram buckets or your own
+/// [`prometheus::Registry`].
+///
+/// The exporter includes the metrics recorded with every enabled library: the `prometheus` crate's registry,
+/// the `metrics` crate, OpenTelemetry, and the `native` metrics library. This way, metrics you record
+/// yourself with any of them are exported alongside the autometrics metrics, for example while migrating
+/// from the `prometheus` crate to OpenTelemetry. If more than one library records the same time series,
+/// such as the autometrics metrics when more than one of the features is enabled, it is exported once.
+///
+/// ```rust
+/// # fn main() -> Result<(), autometrics::ExporterInitializationError> {
+/// let _exporter = autometrics::PrometheusExporterBuilder::new()
+///     .buckets([0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5])
+///     .const_label("service", "api")
+///     .init()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PrometheusExporterBuilder {
+    buckets: Vec<f64>,
+    registry: Option<Registry>,
+    const_labels: Vec<(String, String)>,
+    #[cfg(feature = "metrics")]
+    install_metrics_recorder: bool,
+}
+
+impl Default for PrometheusExporterBuilder {
+    fn default() -> Self {
+        PrometheusExporterBuilder {
+            buckets: DEFAULT_HISTOGRAM_BUCKETS.to_vec(),
+            registry: None,
+            const_labels: Vec::new(),
+            #[cfg(feature = "metrics")]
+            install_metrics_recorder: true,
+        }
+    }
+}
+
+impl PrometheusExporterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the default histogram buckets (in seconds) used to track function latencies.
+    ///
+    /// The buckets configured for individual functions using the `buckets`
+    /// argument of the `autometrics` macro are always included as well.
+    ///
+    /// Note that the exporter should be initialized before any instrumented functions
+    /// are called for the buckets to apply to all of them.
+    pub fn buckets(mut self, buckets: impl Into<Vec<f64>>) -> Self {
+        self.buckets = buckets.into();
+        self
+    }
+
+    /// Register the metrics in the given registry instead of the `prometheus` crate's default registry.
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Add a label with a fixed value to every exported metric,
+    /// for example to identify the service or environment.
+    pub fn const_label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.const_labels.push((name.into(), value.into()));
+        self
+    }
+
+    /// Whether to install the exporter as the global recorder for the `metrics` crate (defaults to `true`).
+    ///
+    /// Set this to `false` if your application installs its own `metrics` recorder.
+    /// Metrics produced using the `metrics` crate will then not be included in the exporter's output.
+    #[cfg(feature = "metrics")]
+    pub fn install_metrics_recorder(mut self, install: bool) -> Self {
+        self.install_metrics_recorder = install;
+        self
+    }
+
+    /// Build the exporter and set it as the global exporter used by
+    /// [`global_metrics_exporter`] and [`encode_global_metrics`].
+    ///
+    /// This returns an error if the global exporter was already initialized.
+    pub fn init(self) -> Result<GlobalPrometheus, ExporterInitializationError> {
+        let mut initialized = false;
+        let exporter = GLOBAL_EXPORTER.get_or_try_init(|| {
+            initialized = true;
+            self.build()
+        })?;
+
+        if initialized {
+            Ok(exporter.clone())
+        } else {
+            Err(ExporterInitializationError::AlreadyInitialized)
+        }
+    }
+
+    fn build(self) -> Result<GlobalPrometheus, ExporterInitializationError> {
+        if !are_valid_buckets(&self.buckets) {
+            return Err(ExporterInitializationError::InvalidBuckets);
+        }
+
+        // The buckets can only be configured per metric, so this uses the default buckets
+        // combined with the buckets configured for individual functions
+        let histogram_buckets = all_histogram_buckets(&self.buckets);
+        set_default_histogram_buckets(self.buckets);
+
+        let registry = match self.registry {
+            Some(registry) => {
+                // The metrics produced with the prometheus crate are registered in its default registry,
+                // so they need to be registered in the custom registry as well
+                #[cfg(feature = "prometheus")]
+                crate::tracker::prometheus::register_collector(&registry)?;
+                registry
+            }
+            // Use the prometheus crate's default registry so it still works with custom
+            // metrics defined through the prometheus crate
+            None => default_registry().clone(),
+        };
+
+        let opentelemetry_registry = Registry::new();
+        let controller = controllers::basic(
+            processors::factory(
+                selectors::simple::histogram(histogram_buckets.clone()),
+                aggregation::cumulative_temporality_selector(),
+            )
+            .with_memory(true),
+        )
+        .build();
+        let prometheus_exporter = exporter(controller)
+            .with_registry(opentelemetry_registry.clone())
+            .try_init()?;
+        // The exporter installs its meter provider globally, which the instruments need to use
+        #[cfg(feature = "opentelemetry")]
+        crate::tracker::opentelemetry::meter_provider_changed();
+        // The native metrics keep their own exemplars
+        #[cfg(all(
+            feature = "exemplars",
+            feature = "opentelemetry",
+            not(feature = "native")
+        ))]
+        crate::exemplars::store::init(histogram_buckets.clone());
+
+        #[cfg(feature = "metrics")]
+        let handle = if self.install_metrics_recorder {
+            let mut builder = PrometheusBuilder::new().set_buckets(&histogram_buckets)?;
+            for (name, value) in &self.const_labels {
+                builder = builder.add_global_label(name, value);
+            }
+            Some(builder.install_recorder()?)
+        } else {
+            None
+        };
+
+        Ok(GlobalPrometheus {
+            registry,
+            opentelemetry_registry,
+            _exporter: prometheus_exporter,
+            const_labels: self.const_labels,
+            #[cfg(feature = "metrics")]
+            handle,
+        })
+    }
+}
+
+/// An error that occurred while initializing the Prometheus exporter
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExporterInitializationError {
+    /// The global exporter was already initialized
+    AlreadyInitialized,
+    /// The histogram buckets must be finite numbers in increasing order
+    InvalidBuckets,
+    /// The metrics could not be registered in the Prometheus registry
+    Prometheus(Error),
+    /// The OpenTelemetry exporter could not be initialized
+    OpenTelemetry(MetricsError),
+    /// The `metrics` crate recorder could not be built or installed,
+    /// for example because another recorder was already installed
+    #[cfg(feature = "metrics")]
+    MetricsRecorder(BuildError),
+}
+
+impl fmt::Display for ExporterInitializationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyInitialized => {
+                f.write_str("the global metrics exporter was already initialized")
+            }
+            Self::InvalidBuckets => {
+                f.write_str("histogram buckets must be finite numbers in increasing order")
+            }
+            Self::Prometheus(err) => write!(f, "failed to register metrics: {err}"),
+            Self::OpenTelemetry(err) => {
+                write!(f, "failed to initialize the OpenTelemetry exporter: {err}")
+            }
+            #[cfg(feature = "metrics")]
+            Self::MetricsRecorder(err) => {
+                write!(f, "failed to install the metrics recorder: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExporterInitializationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Prometheus(err) => Some(err),
+            Self::OpenTelemetry(err) => Some(err),
+            #[cfg(feature = "metrics")]
+            Self::MetricsRecorder(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for ExporterInitializationError {
+    fn from(err: Error) -> Self {
+        Self::Prometheus(err)
+    }
+}
+
+impl From<MetricsError> for ExporterInitializationError {
+    fn from(err: MetricsError) -> Self {
+        Self::OpenTelemetry(err)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl From<BuildError> for ExporterInitializationError {
+    fn from(err: BuildError) -> Self {
+        Self::MetricsRecorder(err)
+    }
+}
+
 /// Initialize the global Prometheus metrics collector and exporter.
 ///
 /// You will need a collector/exporter set up in order to use the metrics
@@ -42,14 +468,29 @@ impl GlobalPrometheus {
 /// [`opentelemetry_prometheus`](https://docs.rs/opentelemetry-prometheus/latest/opentelemetry_prometheus/)
 /// crate documentation.
 ///
+/// This uses the default settings of the [`PrometheusExporterBuilder`].
+/// If the global exporter was already initialized using the builder, this returns that exporter.
+///
 /// This should be included in your `main.rs`:
 /// ```rust
 /// # main() {
 /// let _exporter = global_metrics_exporter();
 /// # }
 /// ```
+///
+/// # Panics
+///
+/// This panics if the exporter cannot be initialized, for example because
+/// another recorder was already installed for the `metrics` crate.
+/// Use the [`PrometheusExporterBuilder`] to handle these errors instead.
 pub fn global_metrics_exporter() -> GlobalPrometheus {
-    GLOBAL_EXPORTER.clone()
+    global_exporter().clone()
+}
+
+fn global_exporter() -> &'static GlobalPrometheus {
+    GLOBAL_EXPORTER
+        .get_or_try_init(|| PrometheusExporterBuilder::new().build())
+        .expect("Failed to initialize the global metrics exporter")
 }
 
 /// Prometheus needs a metrics endpoint to scrape metrics from.
@@ -68,32 +509,25 @@ pub fn global_metrics_exporter() -> GlobalPrometheus {
 /// }
 /// ```
 pub fn encode_global_metrics() -> Result<String, Error> {
-    GLOBAL_EXPORTER.encode_metrics()
+    global_exporter().encode_metrics()
 }
 
-fn initialize_metrics_exporter() -> GlobalPrometheus {
-    let controller = controllers::basic(
-        processors::factory(
-            selectors::simple::histogram(HISTOGRAM_BUCKETS),
-            aggregation::cumulative_temporality_selector(),
-        )
-        .with_memory(true),
-    )
-    .build();
-
-    // Use the prometheus crate's default registry so it still works with custom
-    // metrics defined through the prometheus crate
-    let registry = default_registry().clone();
-    let prometheus_exporter = exporter(controller).with_registry(registry).init();
-
-    GlobalPrometheus {
-        exporter: prometheus_exporter,
-
-        #[cfg(feature = "metrics")]
-        handle: PrometheusBuilder::new()
-            .set_buckets(&HISTOGRAM_BUCKETS)
-            .expect("Failed to set histogram buckets")
-            .install_recorder()
-            .expect("Failed to install recorder"),
-    }
+/// Like [`encode_global_metrics`], but in the given format.
+///
+/// Use [`Format::from_accept_header`] to serve the format that the scraper asks for:
+/// ```rust
+/// # use autometrics::{encode_global_metrics_as, Format};
+/// # let accept_header: Option<&str> = None;
+/// let format = match accept_header {
+///     Some(accept) => Format::from_accept_header(accept),
+///     None => Some(Format::Text),
+/// };
+/// if let Some(format) = format {
+///     let body = encode_global_metrics_as(format).unwrap();
+///     let content_type = format.content_type();
+///     // ...
+/// }
+/// ```
+pub fn encode_global_metrics_as(format: Format) -> Result<Vec<u8>, Error> {
+    global_exporter().encode_as(format)
 }
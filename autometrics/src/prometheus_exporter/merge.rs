@@ -0,0 +1,89 @@
+//! Merges the metric families gathered from different sources, so that each family appears once
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Error;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Combine the families with the same name and sort them by name, with the metrics of each family
+/// sorted by their labels. If more than one source has a time series with the same labels,
+/// the one from the first source is kept.
+///
+/// Labels with empty values are removed, because Prometheus treats them the same as missing labels
+/// and some libraries export them while others leave them out.
+///
+/// This returns an error if the same family has different types in different sources,
+/// because the exposition would be rejected by Prometheus.
+pub(crate) fn merge(metric_families: Vec<MetricFamily>) -> Result<Vec<MetricFamily>, Error> {
+    let mut merged: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for mut family in metric_families {
+        match merged.get_mut(family.get_name()) {
+            Some(existing) => {
+                if existing.get_field_type() != family.get_field_type() {
+                    return Err(Error::Msg(format!(
+                        "the metric family `{}` is exported as both a {} and a {}",
+                        family.get_name(),
+                        type_name(existing.get_field_type()),
+                        type_name(family.get_field_type())
+                    )));
+                }
+                if existing.get_help().is_empty() {
+                    existing.set_help(family.get_help().to_string());
+                }
+                for metric in std::mem::take(family.mut_metric()) {
+                    existing.mut_metric().push(metric);
+                }
+            }
+            None => {
+                merged.insert(family.get_name().to_string(), family);
+            }
+        }
+    }
+
+    Ok(merged
+        .into_values()
+        .filter(|family| !family.get_metric().is_empty())
+        .map(|mut family| {
+            let mut metrics: Vec<Metric> =
+                std::mem::take(family.mut_metric()).into_iter().collect();
+            for metric in &mut metrics {
+                let mut labels: Vec<LabelPair> = std::mem::take(metric.mut_label())
+                    .into_iter()
+                    .filter(|label| !label.get_value().is_empty())
+                    .collect();
+                labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                for label in labels {
+                    metric.mut_label().push(label);
+                }
+            }
+            // The sort is stable, so the first of the duplicates is the one from the first source
+            metrics.sort_by(compare_labels);
+            metrics.dedup_by(|a, b| compare_labels(a, b) == Ordering::Equal);
+            for metric in metrics {
+                family.mut_metric().push(metric);
+            }
+            family
+        })
+        .collect())
+}
+
+fn compare_labels(a: &Metric, b: &Metric) -> Ordering {
+    label_pairs(a).cmp(label_pairs(b))
+}
+
+fn label_pairs(metric: &Metric) -> impl Iterator<Item = (&str, &str)> {
+    metric
+        .get_label()
+        .iter()
+        .map(|label| (label.get_name(), label.get_value()))
+}
+
+fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped metric",
+        MetricType::HISTOGRAM => "histogram",
+    }
+}
@@ -0,0 +1,282 @@
+//! Encodes the metrics gathered from a Prometheus registry in the OpenMetrics text format.
+//!
+//! The `prometheus` crate only includes an encoder for the Prometheus text format. See
+//! https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use crate::constants::{COUNTER_NAME, HISTOGRAM_NAME};
+#[cfg(all(
+    feature = "exemplars",
+    feature = "opentelemetry",
+    not(feature = "native")
+))]
+use crate::exemplars::{store::Exemplars, WithExemplar};
+use crate::text::{escape, format_float, write_sample, HISTOGRAM_NAME_OPENMETRICS, HISTOGRAM_UNIT};
+use const_format::str_replace;
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use std::fmt::{self, Write};
+
+const COUNTER_NAME_PROMETHEUS: &str = str_replace!(COUNTER_NAME, ".", "_");
+const HISTOGRAM_NAME_PROMETHEUS: &str = str_replace!(HISTOGRAM_NAME, ".", "_");
+
+/// Write the metric families to the output, without the `# EOF` line.
+///
+/// The metrics libraries do not expose when a time series was created,
+/// so unlike the native metrics, these do not have `_created` samples.
+pub(crate) fn encode(output: &mut String, metric_families: &[MetricFamily]) {
+    // Writing to a String cannot fail
+    let _ = write_families(output, metric_families);
+}
+
+fn write_families(output: &mut String, metric_families: &[MetricFamily]) -> fmt::Result {
+    let exemplars = Exemplars::get();
+    for family in metric_families {
+        let name = family.get_name();
+        let (family_name, metric_type) = match family.get_field_type() {
+            // OpenMetrics names the counter family without the `_total` suffix of its samples
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            // The name of the autometrics histogram needs to end with its unit
+            MetricType::HISTOGRAM if name == HISTOGRAM_NAME_PROMETHEUS => {
+                (HISTOGRAM_NAME_OPENMETRICS, "histogram")
+            }
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        if !family.get_help().is_empty() {
+            writeln!(
+                output,
+                "# HELP {family_name} {}",
+                escape(family.get_help(), true)
+            )?;
+        }
+        writeln!(output, "# TYPE {family_name} {metric_type}")?;
+        if let Some(unit) = unit(family_name) {
+            writeln!(output, "# UNIT {family_name} {unit}")?;
+        }
+
+        for metric in family.get_metric() {
+            // Only the autometrics metrics have exemplars
+            let exemplars = match family_name {
+                COUNTER_NAME_PROMETHEUS | HISTOGRAM_NAME_OPENMETRICS => Some(&exemplars),
+                _ => None,
+            };
+            write_metric(
+                output,
+                family_name,
+                family.get_field_type(),
+                metric,
+                exemplars,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The base units used in Prometheus metric names
+const UNITS: &[&str] = &[
+    HISTOGRAM_UNIT,
+    "bytes",
+    "ratio",
+    "meters",
+    "grams",
+    "joules",
+    "volts",
+    "amperes",
+    "celsius",
+];
+
+/// OpenMetrics only allows a unit if the name of the metric family ends with it,
+/// so the unit is taken from the name
+fn unit(family_name: &str) -> Option<&'static str> {
+    UNITS.iter().copied().find(|unit| {
+        family_name
+            .strip_suffix(unit)
+            .is_some_and(|prefix| prefix.ends_with('_'))
+    })
+}
+
+fn label_pairs(labels: &[LabelPair]) -> impl Iterator<Item = (&str, &str)> {
+    labels
+        .iter()
+        .map(|label| (label.get_name(), label.get_value()))
+}
+
+fn write_metric(
+    output: &mut String,
+    name: &str,
+    metric_type: MetricType,
+    metric: &Metric,
+    exemplars: Option<&Exemplars>,
+) -> fmt::Result {
+    let labels = metric.get_label();
+    match metric_type {
+        MetricType::COUNTER => write_sample(
+            output,
+            &format!("{name}_total"),
+            label_pairs(labels),
+            counter_value(exemplars, labels, metric.get_counter().get_value()),
+        ),
+        MetricType::GAUGE => write_sample(
+            output,
+            name,
+            label_pairs(labels),
+            format_float(metric.get_gauge().get_value()),
+        ),
+        MetricType::UNTYPED => write_sample(
+            output,
+            name,
+            label_pairs(labels),
+            format_float(metric.get_untyped().get_value()),
+        ),
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            let bucket_name = format!("{name}_bucket");
+            let mut has_inf_bucket = false;
+            for bucket in histogram.get_bucket() {
+                let upper_bound = bucket.get_upper_bound();
+                has_inf_bucket |= upper_bound == f64::INFINITY;
+                write_sample(
+                    output,
+                    &bucket_name,
+                    label_pairs(labels).chain([("le", &*format_float(upper_bound))]),
+                    bucket_value(
+                        exemplars,
+                        labels,
+                        upper_bound,
+                        bucket.get_cumulative_count(),
+                    ),
+                )?;
+            }
+            if !has_inf_bucket {
+                write_sample(
+                    output,
+                    &bucket_name,
+                    label_pairs(labels).chain([("le", "+Inf")]),
+                    bucket_value(
+                        exemplars,
+                        labels,
+                        f64::INFINITY,
+                        histogram.get_sample_count(),
+                    ),
+                )?;
+            }
+            write_sample(
+                output,
+                &format!("{name}_sum"),
+                label_pairs(labels),
+                format_float(histogram.get_sample_sum()),
+            )?;
+            write_sample(
+                output,
+                &format!("{name}_count"),
+                label_pairs(labels),
+                histogram.get_sample_count(),
+            )
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            for quantile in summary.get_quantile() {
+                write_sample(
+                    output,
+                    name,
+                    label_pairs(labels)
+                        .chain([("quantile", &*format_float(quantile.get_quantile()))]),
+                    format_float(quantile.get_value()),
+                )?;
+            }
+            write_sample(
+                output,
+                &format!("{name}_sum"),
+                label_pairs(labels),
+                format_float(summary.get_sample_sum()),
+            )?;
+            write_sample(
+                output,
+                &format!("{name}_count"),
+                label_pairs(labels),
+                summary.get_sample_count(),
+            )
+        }
+    }
+}
+
+/// The exemplars recorded by the OpenTelemetry tracker
+#[cfg(not(all(
+    feature = "exemplars",
+    feature = "opentelemetry",
+    not(feature = "native")
+)))]
+struct Exemplars;
+
+#[cfg(not(all(
+    feature = "exemplars",
+    feature = "opentelemetry",
+    not(feature = "native")
+)))]
+impl Exemplars {
+    fn get() -> Self {
+        Exemplars
+    }
+}
+
+/// The value of a counter, followed by its exemplar if it has one
+#[cfg(all(
+    feature = "exemplars",
+    feature = "opentelemetry",
+    not(feature = "native")
+))]
+fn counter_value(
+    exemplars: Option<&Exemplars>,
+    labels: &[LabelPair],
+    value: f64,
+) -> impl fmt::Display {
+    let exemplar =
+        exemplars.and_then(|exemplars| exemplars.counter(&label_pairs(labels).collect::<Vec<_>>()));
+    WithExemplar(format_float(value), exemplar)
+}
+
+#[cfg(not(all(
+    feature = "exemplars",
+    feature = "opentelemetry",
+    not(feature = "native")
+)))]
+fn counter_value(
+    _exemplars: Option<&Exemplars>,
+    _labels: &[LabelPair],
+    value: f64,
+) -> impl fmt::Display {
+    format_float(value)
+}
+
+/// The count of a histogram bucket, followed by the bucket's exemplar if it has one
+#[cfg(all(
+    feature = "exemplars",
+    feature = "opentelemetry",
+    not(feature = "native")
+))]
+fn bucket_value(
+    exemplars: Option<&Exemplars>,
+    labels: &[LabelPair],
+    upper_bound: f64,
+    count: u64,
+) -> impl fmt::Display {
+    let exemplar = exemplars.and_then(|exemplars| {
+        exemplars.bucket(&label_pairs(labels).collect::<Vec<_>>(), upper_bound)
+    });
+    WithExemplar(count, exemplar)
+}
+
+#[cfg(not(all(
+    feature = "exemplars",
+    feature = "opentelemetry",
+    not(feature = "native")
+)))]
+fn bucket_value(
+    _exemplars: Option<&Exemplars>,
+    _labels: &[LabelPair],
+    _upper_bound: f64,
+    count: u64,
+) -> impl fmt::Display {
+    count
+}
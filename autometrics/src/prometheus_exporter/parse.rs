@@ -0,0 +1,310 @@
+//! Parses the Prometheus text format rendered by the `metrics` crate's recorder,
+//! so that its metrics can be merged with the ones gathered from the registry.
+//!
+//! This only supports the subset of the format that `metrics-exporter-prometheus` produces. See
+//! https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+
+use prometheus::proto::{
+    Bucket, Counter, Gauge, Histogram, LabelPair, Metric, MetricFamily, MetricType, Quantile,
+    Summary, Untyped,
+};
+use prometheus::Error;
+
+type Labels = Vec<(String, String)>;
+
+struct ParsedFamily {
+    name: String,
+    help: String,
+    metric_type: MetricType,
+    metrics: Vec<ParsedMetric>,
+}
+
+/// The samples of one time series. Histograms and summaries combine the samples
+/// that have the same labels, other than `le` or `quantile`.
+#[derive(Default)]
+struct ParsedMetric {
+    labels: Labels,
+    timestamp_ms: Option<i64>,
+    value: f64,
+    /// The upper bounds and cumulative counts of a histogram, or the quantiles of a summary
+    buckets: Vec<(f64, f64)>,
+    sum: f64,
+    count: f64,
+}
+
+/// Parse the output into metric families, in the order in which they first appear
+pub(crate) fn parse(text: &str) -> Result<Vec<MetricFamily>, Error> {
+    let mut families = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let result = if let Some(comment) = line.strip_prefix('#') {
+            parse_comment(&mut families, comment.trim_start())
+        } else if line.is_empty() {
+            Ok(())
+        } else {
+            parse_sample(&mut families, line)
+        };
+        result.map_err(|message| {
+            Error::Msg(format!(
+                "failed to parse line {} of the metrics crate's output: {message}",
+                line_number + 1
+            ))
+        })?;
+    }
+
+    Ok(families.into_iter().map(into_metric_family).collect())
+}
+
+fn family_index(families: &mut Vec<ParsedFamily>, name: &str) -> usize {
+    match families.iter().position(|family| family.name == name) {
+        Some(index) => index,
+        None => {
+            families.push(ParsedFamily {
+                name: name.to_string(),
+                help: String::new(),
+                metric_type: MetricType::UNTYPED,
+                metrics: Vec::new(),
+            });
+            families.len() - 1
+        }
+    }
+}
+
+fn parse_comment(families: &mut Vec<ParsedFamily>, comment: &str) -> Result<(), String> {
+    let mut parts = comment.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("HELP"), Some(name), help) => {
+            let index = family_index(families, name);
+            families[index].help = unescape(help.unwrap_or_default());
+        }
+        (Some("TYPE"), Some(name), Some(metric_type)) => {
+            let metric_type = match metric_type.trim() {
+                "counter" => MetricType::COUNTER,
+                "gauge" => MetricType::GAUGE,
+                "histogram" => MetricType::HISTOGRAM,
+                "summary" => MetricType::SUMMARY,
+                "untyped" => MetricType::UNTYPED,
+                other => return Err(format!("unknown metric type `{other}`")),
+            };
+            let index = family_index(families, name);
+            families[index].metric_type = metric_type;
+        }
+        // Other comments are ignored
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_sample(families: &mut Vec<ParsedFamily>, line: &str) -> Result<(), String> {
+    let name_end = line.find(['{', ' ']).ok_or("missing sample value")?;
+    let (name, rest) = line.split_at(name_end);
+    let (mut labels, rest) = match rest.strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (Vec::new(), rest),
+    };
+
+    let mut values = rest.split_whitespace();
+    let value = parse_float(values.next().ok_or("missing sample value")?)?;
+    let timestamp_ms = match values.next() {
+        Some(timestamp) => Some(
+            timestamp
+                .parse::<i64>()
+                .map_err(|_| format!("invalid timestamp `{timestamp}`"))?,
+        ),
+        None => None,
+    };
+
+    // The samples of histograms and summaries have a suffix after the name of their family
+    let index = families.iter().position(|family| {
+        matches!(
+            (family.metric_type, name.strip_prefix(family.name.as_str())),
+            (_, Some(""))
+                | (
+                    MetricType::HISTOGRAM | MetricType::SUMMARY,
+                    Some("_sum" | "_count")
+                )
+                | (MetricType::HISTOGRAM, Some("_bucket"))
+        )
+    });
+    let index = match index {
+        Some(index) => index,
+        // Samples without a TYPE line are untyped
+        None => family_index(families, name),
+    };
+    let family = &mut families[index];
+    let suffix = &name[family.name.len()..];
+
+    let bucket_label = match (family.metric_type, suffix) {
+        (MetricType::HISTOGRAM, "_bucket") => Some("le"),
+        (MetricType::SUMMARY, "") => Some("quantile"),
+        _ => None,
+    };
+    let bucket = match bucket_label {
+        Some(bucket_label) => {
+            let position = labels
+                .iter()
+                .position(|(key, _)| key == bucket_label)
+                .ok_or_else(|| format!("missing `{bucket_label}` label"))?;
+            Some(parse_float(&labels.remove(position).1)?)
+        }
+        None => None,
+    };
+
+    let metric = match family
+        .metrics
+        .iter()
+        .position(|metric| metric.labels == labels)
+    {
+        Some(index) => &mut family.metrics[index],
+        None => {
+            family.metrics.push(ParsedMetric {
+                labels,
+                ..Default::default()
+            });
+            family
+                .metrics
+                .last_mut()
+                .expect("the metric was just added")
+        }
+    };
+    if timestamp_ms.is_some() {
+        metric.timestamp_ms = timestamp_ms;
+    }
+    match (bucket, suffix) {
+        (Some(bucket), _) => metric.buckets.push((bucket, value)),
+        (None, "_sum") => metric.sum = value,
+        (None, "_count") => metric.count = value,
+        (None, _) => metric.value = value,
+    }
+    Ok(())
+}
+
+fn into_metric_family(parsed: ParsedFamily) -> MetricFamily {
+    let mut family = MetricFamily::new();
+    family.set_name(parsed.name);
+    family.set_help(parsed.help);
+    family.set_field_type(parsed.metric_type);
+
+    for parsed_metric in parsed.metrics {
+        let mut metric = Metric::new();
+        for (name, value) in parsed_metric.labels {
+            let mut label = LabelPair::new();
+            label.set_name(name);
+            label.set_value(value);
+            metric.mut_label().push(label);
+        }
+        if let Some(timestamp_ms) = parsed_metric.timestamp_ms {
+            metric.set_timestamp_ms(timestamp_ms);
+        }
+
+        match parsed.metric_type {
+            MetricType::COUNTER => {
+                let mut counter = Counter::new();
+                counter.set_value(parsed_metric.value);
+                metric.set_counter(counter);
+            }
+            MetricType::GAUGE => {
+                let mut gauge = Gauge::new();
+                gauge.set_value(parsed_metric.value);
+                metric.set_gauge(gauge);
+            }
+            MetricType::UNTYPED => {
+                let mut untyped = Untyped::new();
+                untyped.set_value(parsed_metric.value);
+                metric.set_untyped(untyped);
+            }
+            MetricType::HISTOGRAM => {
+                let mut histogram = Histogram::new();
+                for (upper_bound, cumulative_count) in parsed_metric.buckets {
+                    let mut bucket = Bucket::new();
+                    bucket.set_upper_bound(upper_bound);
+                    bucket.set_cumulative_count(cumulative_count as u64);
+                    histogram.mut_bucket().push(bucket);
+                }
+                histogram.set_sample_sum(parsed_metric.sum);
+                histogram.set_sample_count(parsed_metric.count as u64);
+                metric.set_histogram(histogram);
+            }
+            MetricType::SUMMARY => {
+                let mut summary = Summary::new();
+                for (quantile, value) in parsed_metric.buckets {
+                    let mut encoded = Quantile::new();
+                    encoded.set_quantile(quantile);
+                    encoded.set_value(value);
+                    summary.mut_quantile().push(encoded);
+                }
+                summary.set_sample_sum(parsed_metric.sum);
+                summary.set_sample_count(parsed_metric.count as u64);
+                metric.set_summary(summary);
+            }
+        }
+        family.mut_metric().push(metric);
+    }
+    family
+}
+
+/// Parse the labels following the opening brace, and return the rest of the line after the closing brace
+fn parse_labels(mut rest: &str) -> Result<(Labels, &str), String> {
+    let mut labels = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(rest) = rest.strip_prefix('}') {
+            return Ok((labels, rest));
+        }
+
+        let (key, value) = rest.split_once('=').ok_or("invalid labels")?;
+        let value = value
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or("label values need to be quoted")?;
+
+        // Find the closing quote, skipping escaped characters
+        let mut value_end = None;
+        let mut escaped = false;
+        for (i, c) in value.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    value_end = Some(i);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let value_end = value_end.ok_or("unterminated label value")?;
+        labels.push((key.trim().to_string(), unescape(&value[..value_end])));
+
+        rest = value[value_end + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+}
+
+fn parse_float(value: &str) -> Result<f64, String> {
+    match value {
+        "+Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        "NaN" => Ok(f64::NAN),
+        _ => value
+            .parse()
+            .map_err(|_| format!("invalid sample value `{value}`")),
+    }
+}
+
+/// Undo the escaping of backslashes, newlines, and double quotes
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => unescaped.push('\\'),
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
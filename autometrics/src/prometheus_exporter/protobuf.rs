@@ -0,0 +1,92 @@
+//! Encodes the metrics gathered from a Prometheus registry in the Prometheus protobuf format.
+//!
+//! The `prometheus` crate can only do this with its `protobuf` feature, which adds a dependency
+//! on a protobuf library, so this uses the same minimal encoder as the `native` metrics library.
+
+use crate::protobuf::*;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+
+// MetricType values that autometrics itself does not use
+const TYPE_SUMMARY: u64 = 2;
+const TYPE_UNTYPED: u64 = 3;
+
+/// Write each metric family to the output as a length-delimited `MetricFamily` message
+pub(crate) fn encode(output: &mut Vec<u8>, metric_families: &[MetricFamily]) {
+    for family in metric_families {
+        let metric_type = match family.get_field_type() {
+            MetricType::COUNTER => TYPE_COUNTER,
+            MetricType::GAUGE => TYPE_GAUGE,
+            MetricType::SUMMARY => TYPE_SUMMARY,
+            MetricType::UNTYPED => TYPE_UNTYPED,
+            MetricType::HISTOGRAM => TYPE_HISTOGRAM,
+        };
+        let mut message = self::family(family.get_name(), family.get_help(), metric_type);
+        for metric in family.get_metric() {
+            write_message(
+                &mut message,
+                4,
+                &encode_metric(family.get_field_type(), metric),
+            );
+        }
+        write_delimited(output, &message);
+    }
+}
+
+fn encode_metric(metric_type: MetricType, metric: &Metric) -> Vec<u8> {
+    let mut message = self::metric(
+        metric
+            .get_label()
+            .iter()
+            .map(|label| (label.get_name(), label.get_value())),
+    );
+
+    match metric_type {
+        MetricType::COUNTER => {
+            let mut counter = Vec::new();
+            write_double(&mut counter, 1, metric.get_counter().get_value());
+            write_message(&mut message, 3, &counter);
+        }
+        MetricType::GAUGE => {
+            let mut gauge = Vec::new();
+            write_double(&mut gauge, 1, metric.get_gauge().get_value());
+            write_message(&mut message, 2, &gauge);
+        }
+        MetricType::UNTYPED => {
+            let mut untyped = Vec::new();
+            write_double(&mut untyped, 1, metric.get_untyped().get_value());
+            write_message(&mut message, 5, &untyped);
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            let mut encoded = Vec::new();
+            write_varint_field(&mut encoded, 1, summary.get_sample_count());
+            write_double(&mut encoded, 2, summary.get_sample_sum());
+            for quantile in summary.get_quantile() {
+                let mut encoded_quantile = Vec::new();
+                write_double(&mut encoded_quantile, 1, quantile.get_quantile());
+                write_double(&mut encoded_quantile, 2, quantile.get_value());
+                write_message(&mut encoded, 3, &encoded_quantile);
+            }
+            write_message(&mut message, 4, &encoded);
+        }
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            let mut encoded = Vec::new();
+            write_varint_field(&mut encoded, 1, histogram.get_sample_count());
+            write_double(&mut encoded, 2, histogram.get_sample_sum());
+            for bucket in histogram.get_bucket() {
+                let mut encoded_bucket = Vec::new();
+                write_varint_field(&mut encoded_bucket, 1, bucket.get_cumulative_count());
+                write_double(&mut encoded_bucket, 2, bucket.get_upper_bound());
+                write_message(&mut encoded, 3, &encoded_bucket);
+            }
+            write_message(&mut message, 7, &encoded);
+        }
+    }
+
+    if metric.get_timestamp_ms() != 0 {
+        // int64 values are encoded as varints of their two's complement
+        write_varint_field(&mut message, 6, metric.get_timestamp_ms() as u64);
+    }
+    message
+}
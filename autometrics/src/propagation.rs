@@ -0,0 +1,239 @@
+//! Propagate the `caller` label across service boundaries.
+//!
+//! The client injects the name of the instrumented function that makes a request into the
+//! outgoing request (either as HTTP headers or as OpenTelemetry baggage). The server extracts it
+//! and runs its entry handler with [`with_remote_caller`], so the handler reports the remote
+//! function as its `caller`. This makes the callee queries in the generated documentation work
+//! across services that are scraped by the same Prometheus instance.
+
+use crate::{
+    __private::CALLER,
+    caller::current_caller,
+    constants::{CALLER_KEY, CALLER_SERVICE_KEY},
+    labels::intern_label_value,
+    task_local::LocalKey,
+};
+use once_cell::sync::OnceCell;
+use std::{cell::RefCell, future::Future, ptr, thread_local};
+
+/// The HTTP header used to propagate the name of the calling function
+pub const CALLER_HEADER: &str = "x-autometrics-caller";
+/// The HTTP header used to propagate the name of the calling service
+pub const CALLER_SERVICE_HEADER: &str = "x-autometrics-caller-service";
+
+#[cfg(feature = "opentelemetry")]
+const CALLER_BAGGAGE_KEY: &str = "autometrics.caller";
+#[cfg(feature = "opentelemetry")]
+const CALLER_SERVICE_BAGGAGE_KEY: &str = "autometrics.caller_service";
+
+/// The maximum length of the function and service names received from other services
+const MAX_REMOTE_CALLER_LENGTH: usize = 128;
+
+static SERVICE_NAME: OnceCell<&'static str> = OnceCell::new();
+
+/// Task-local value for the remote caller of the current entry handler
+static REMOTE_CALLER: LocalKey<Option<RemoteCaller>> = {
+    thread_local! {
+        static REMOTE_CALLER_KEY: RefCell<Option<Option<RemoteCaller>>> = const { RefCell::new(Some(None)) };
+    }
+
+    LocalKey {
+        inner: REMOTE_CALLER_KEY,
+    }
+};
+
+/// Set the name of this service, which is sent along with the caller so that
+/// the receiving service can record it in the `caller_service` label.
+///
+/// Only the first call has an effect.
+pub fn set_service_name(name: impl Into<String>) {
+    SERVICE_NAME.get_or_init(|| Box::leak(name.into().into_boxed_str()));
+}
+
+/// The function (and optionally the service) that made a request to this service
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RemoteCaller {
+    function: &'static str,
+    service: Option<&'static str>,
+}
+
+impl RemoteCaller {
+    /// The values are interned like dynamic labels, so the number of
+    /// distinct callers that will be recorded is limited.
+    ///
+    /// The name usually comes from another service, so this returns `None` if it is empty,
+    /// longer than 128 bytes, or contains characters other than ASCII letters, digits, and `_-.:/<>()*&[]`.
+    pub fn new(function: impl AsRef<str>) -> Option<Self> {
+        let function = function.as_ref();
+        is_valid_remote_name(function).then(|| RemoteCaller {
+            function: intern_label_value(CALLER_KEY, function),
+            service: None,
+        })
+    }
+
+    /// Set the name of the service the caller belongs to.
+    ///
+    /// The service is left out if its name is not valid, like the function name in [`RemoteCaller::new`].
+    pub fn with_service(mut self, service: impl AsRef<str>) -> Self {
+        let service = service.as_ref();
+        if is_valid_remote_name(service) {
+            self.service = Some(intern_label_value(CALLER_SERVICE_KEY, service));
+        }
+        self
+    }
+
+    pub fn function(&self) -> &'static str {
+        self.function
+    }
+
+    pub fn service(&self) -> Option<&'static str> {
+        self.service
+    }
+}
+
+/// Names received from other services are checked before they are interned,
+/// so that a client cannot fill the interned values with arbitrary data
+fn is_valid_remote_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_REMOTE_CALLER_LENGTH
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"_-.:/<>()*&[]".contains(&byte))
+}
+
+/// Add the current caller (and the name of this service, if set) to an outgoing request
+/// by calling `set_header` with each header name and value.
+///
+/// This does nothing if it is not called from within an instrumented function.
+///
+/// ```rust,ignore
+/// #[autometrics]
+/// async fn get_user(client: &reqwest::Client) -> reqwest::Result<User> {
+///     let mut headers = Vec::new();
+///     autometrics::inject_caller(|name, value| headers.push((name, value)));
+///
+///     let mut request = client.get("http://users/user/1");
+///     for (name, value) in headers {
+///         request = request.header(name, value);
+///     }
+///     request.send().await?.json().await
+/// }
+/// ```
+pub fn inject_caller(mut set_header: impl FnMut(&'static str, &'static str)) {
+    let caller = current_caller();
+    if caller.is_empty() {
+        return;
+    }
+
+    set_header(CALLER_HEADER, caller);
+    if let Some(service) = SERVICE_NAME.get().copied() {
+        set_header(CALLER_SERVICE_HEADER, service);
+    }
+}
+
+/// Read the remote caller from an incoming request, using `get_header` to look up the header values.
+///
+/// This returns `None` if the header is missing or its value is not valid, see [`RemoteCaller::new`].
+pub fn extract_caller<'a>(
+    get_header: impl Fn(&'static str) -> Option<&'a str>,
+) -> Option<RemoteCaller> {
+    let remote_caller = RemoteCaller::new(get_header(CALLER_HEADER)?)?;
+    Some(match get_header(CALLER_SERVICE_HEADER) {
+        Some(service) => remote_caller.with_service(service),
+        None => remote_caller,
+    })
+}
+
+/// Add the current caller to the headers of an outgoing request, see [`inject_caller`].
+#[cfg(feature = "tower")]
+pub fn inject_caller_headers(headers: &mut http::HeaderMap) {
+    inject_caller(|name, value| {
+        if let Ok(value) = http::HeaderValue::from_str(value) {
+            headers.insert(name, value);
+        }
+    });
+}
+
+/// Read the remote caller from the headers of an incoming request, see [`extract_caller`].
+#[cfg(feature = "tower")]
+pub fn extract_caller_headers(headers: &http::HeaderMap) -> Option<RemoteCaller> {
+    extract_caller(|name| headers.get(name).and_then(|value| value.to_str().ok()))
+}
+
+/// Return a copy of the OpenTelemetry context with the current caller added to its baggage,
+/// so that it is propagated along with the context.
+#[cfg(feature = "opentelemetry")]
+pub fn inject_caller_baggage(context: &opentelemetry_api::Context) -> opentelemetry_api::Context {
+    use opentelemetry_api::{baggage::BaggageExt, KeyValue};
+
+    let mut baggage = Vec::new();
+    inject_caller(|name, value| {
+        let key = if name == CALLER_HEADER {
+            CALLER_BAGGAGE_KEY
+        } else {
+            CALLER_SERVICE_BAGGAGE_KEY
+        };
+        baggage.push(KeyValue::new(key, value));
+    });
+
+    if baggage.is_empty() {
+        context.clone()
+    } else {
+        context.with_baggage(baggage)
+    }
+}
+
+/// Read the remote caller from the baggage of an OpenTelemetry context.
+#[cfg(feature = "opentelemetry")]
+pub fn extract_caller_baggage(context: &opentelemetry_api::Context) -> Option<RemoteCaller> {
+    use opentelemetry_api::baggage::BaggageExt;
+
+    let baggage = context.baggage();
+    let remote_caller = RemoteCaller::new(baggage.get(CALLER_BAGGAGE_KEY)?.as_str())?;
+    Some(match baggage.get(CALLER_SERVICE_BAGGAGE_KEY) {
+        Some(service) => remote_caller.with_service(service.as_str()),
+        None => remote_caller,
+    })
+}
+
+/// Run the future (usually the entry handler for a request) with the remote function as its `caller`.
+///
+/// If the remote caller includes the service name, the instrumented function that is called
+/// directly by the future is also counted with a `caller_service` label.
+///
+/// ```rust,ignore
+/// async fn handler(headers: HeaderMap) -> Response {
+///     autometrics::with_remote_caller(autometrics::extract_caller_headers(&headers), get_user()).await
+/// }
+/// ```
+pub fn with_remote_caller<F: Future>(
+    remote_caller: Option<RemoteCaller>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    let caller = remote_caller.map_or("", |remote| remote.function);
+    CALLER.scope(caller, REMOTE_CALLER.scope(remote_caller, future))
+}
+
+/// Wrap the closure so that it runs with the remote function as its `caller`, see [`with_remote_caller`].
+///
+/// Like [`with_caller_fn`](crate::with_caller_fn), this returns the wrapped closure instead of calling it.
+pub fn with_remote_caller_fn<F, R>(remote_caller: Option<RemoteCaller>, f: F) -> impl FnOnce() -> R
+where
+    F: FnOnce() -> R,
+{
+    let caller = remote_caller.map_or("", |remote| remote.function);
+    move || CALLER.sync_scope(caller, || REMOTE_CALLER.sync_scope(remote_caller, f))
+}
+
+/// The service of the remote caller, if `caller` is the function of the remote caller.
+///
+/// Instrumented functions replace the caller for the functions they call, so this
+/// only applies to the function called directly by the remote caller.
+pub(crate) fn caller_service(caller: &'static str) -> Option<&'static str> {
+    REMOTE_CALLER
+        .try_with(|remote_caller| *remote_caller)
+        .ok()
+        .flatten()
+        .filter(|remote_caller| ptr::eq(remote_caller.function, caller))
+        .and_then(|remote_caller| remote_caller.service)
+}
@@ -0,0 +1,104 @@
+//! Helpers for writing the Prometheus protobuf exposition format without depending on a protobuf library.
+//!
+//! The output is a sequence of length-delimited `io.prometheus.client.MetricFamily` messages. See
+//! https://github.com/prometheus/client_model/blob/master/io/prometheus/client/metrics.proto
+//!
+//! The wire format helpers are also used to encode the OTLP requests of the `otlp-exporter`.
+
+#[cfg(feature = "native")]
+use std::time::Duration;
+
+// MetricType
+#[cfg(any(feature = "native", feature = "prometheus-exporter"))]
+pub(crate) const TYPE_COUNTER: u64 = 0;
+#[cfg(any(feature = "native", feature = "prometheus-exporter"))]
+pub(crate) const TYPE_GAUGE: u64 = 1;
+#[cfg(any(feature = "native", feature = "prometheus-exporter"))]
+pub(crate) const TYPE_HISTOGRAM: u64 = 4;
+
+// Wire types
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+
+/// Start a `MetricFamily` message, to which the `Metric`s are added as field 4
+#[cfg(any(feature = "native", feature = "prometheus-exporter"))]
+pub(crate) fn family(name: &str, help: &str, metric_type: u64) -> Vec<u8> {
+    let mut family = Vec::new();
+    write_bytes(&mut family, 1, name.as_bytes());
+    write_bytes(&mut family, 2, help.as_bytes());
+    write_varint_field(&mut family, 3, metric_type);
+    family
+}
+
+/// Start a `Metric` message with the given labels
+#[cfg(any(feature = "native", feature = "prometheus-exporter"))]
+pub(crate) fn metric<'a>(labels: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut metric = Vec::new();
+    for (name, value) in labels {
+        write_label(&mut metric, 1, name, value);
+    }
+    metric
+}
+
+/// Write a `LabelPair` message
+#[cfg(any(feature = "native", feature = "prometheus-exporter"))]
+pub(crate) fn write_label(output: &mut Vec<u8>, field: u64, name: &str, value: &str) {
+    let mut label = Vec::new();
+    write_bytes(&mut label, 1, name.as_bytes());
+    write_bytes(&mut label, 2, value.as_bytes());
+    write_message(output, field, &label);
+}
+
+/// Write a `google.protobuf.Timestamp` message for the given time since the Unix epoch
+#[cfg(feature = "native")]
+pub(crate) fn write_timestamp(output: &mut Vec<u8>, field: u64, timestamp: Duration) {
+    let mut message = Vec::new();
+    write_varint_field(&mut message, 1, timestamp.as_secs());
+    write_varint_field(&mut message, 2, timestamp.subsec_nanos().into());
+    write_message(output, field, &message);
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn write_key(output: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(output, (field << 3) | wire_type);
+}
+
+pub(crate) fn write_varint_field(output: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(output, field, VARINT);
+    write_varint(output, value);
+}
+
+pub(crate) fn write_double(output: &mut Vec<u8>, field: u64, value: f64) {
+    write_key(output, field, FIXED64);
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(feature = "otlp-exporter")]
+pub(crate) fn write_fixed64(output: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(output, field, FIXED64);
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn write_bytes(output: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(output, field, LENGTH_DELIMITED);
+    write_delimited(output, bytes);
+}
+
+pub(crate) fn write_message(output: &mut Vec<u8>, field: u64, message: &[u8]) {
+    write_bytes(output, field, message);
+}
+
+/// Write the length of the bytes followed by the bytes,
+/// which is also how each `MetricFamily` is written to the output
+pub(crate) fn write_delimited(output: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(output, bytes.len() as u64);
+    output.extend_from_slice(bytes);
+}
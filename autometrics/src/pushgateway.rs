@@ -0,0 +1,267 @@
+use crate::encode_global_metrics;
+use crate::http_client::{self, Url};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The push when the client is dropped is not retried and uses a short timeout,
+/// so that dropping the client cannot hold up the process for long
+const DROP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Builder for a [`Pushgateway`] client, which pushes the metrics from
+/// [`encode_global_metrics`] to a [Prometheus Pushgateway](https://github.com/prometheus/pushgateway).
+///
+/// Use this for batch jobs that exit before Prometheus could scrape them.
+///
+/// `https://` URLs are supported with the `tls` feature. Without it, an `https://` URL is rejected
+/// with [`PushgatewayError::InvalidUrl`].
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), autometrics::PushgatewayError> {
+/// let pushgateway = autometrics::PushgatewayBuilder::new("http://localhost:9091", "nightly_import")
+///     .grouping_key("instance", "worker-1")
+///     .build()?;
+/// // ...
+/// pushgateway.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PushgatewayBuilder {
+    url: String,
+    job: String,
+    grouping_keys: Vec<(String, String)>,
+    retries: u32,
+    backoff: Duration,
+    timeout: Duration,
+}
+
+impl PushgatewayBuilder {
+    /// Push the metrics to the Pushgateway at the given `http://` URL, or `https://` URL
+    /// if the `tls` feature is enabled, grouped under the given job name.
+    pub fn new(url: impl Into<String>, job: impl Into<String>) -> Self {
+        PushgatewayBuilder {
+            url: url.into(),
+            job: job.into(),
+            grouping_keys: Vec::new(),
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Add a label to the grouping key, such as `instance`, in addition to the `job`.
+    ///
+    /// Each push replaces the metrics that were previously pushed with the same grouping key.
+    pub fn grouping_key(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.grouping_keys.push((name.into(), value.into()));
+        self
+    }
+
+    /// How many times to retry a push that failed because the Pushgateway could not be reached
+    /// or responded with a server error (defaults to 3).
+    ///
+    /// The delay before the first retry is set with [`backoff`](Self::backoff)
+    /// and doubles after every retry.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait before retrying a failed push for the first time (defaults to 500 milliseconds)
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// How long to wait for the Pushgateway on each attempt (defaults to 10 seconds)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Build the client. This returns an error if the URL is not a valid `http://` URL,
+    /// or `https://` URL with the `tls` feature.
+    pub fn build(self) -> Result<Pushgateway, PushgatewayError> {
+        let url = Url::parse(&self.url).map_err(PushgatewayError::InvalidUrl)?;
+
+        // The grouping key is part of the path, for example /metrics/job/some_job/instance/some_instance
+        let mut path = format!(
+            "{}/metrics/{}",
+            url.path().trim_end_matches('/'),
+            path_segment("job", &self.job)
+        );
+        for (name, value) in &self.grouping_keys {
+            path.push('/');
+            path.push_str(&path_segment(name, value));
+        }
+
+        Ok(Pushgateway {
+            url: url.with_path(path),
+            retries: self.retries,
+            backoff: self.backoff,
+            timeout: self.timeout,
+            flushed: AtomicBool::new(false),
+        })
+    }
+}
+
+/// Client that pushes the metrics to a Prometheus Pushgateway, built with the [`PushgatewayBuilder`].
+///
+/// Call [`push`](Self::push) to push the current metrics at any time, and [`flush`](Self::flush)
+/// before the process exits. If the client is dropped without being flushed, it pushes the metrics
+/// one last time, without retrying and with a short timeout, and ignores any error.
+/// It does not push when it is dropped while the thread is panicking.
+///
+/// Do not rely on that to push the final metrics, though: destructors do not run when the process
+/// calls [`std::process::exit`] or panics with `panic = "abort"`, or for clients stored in a `static`.
+pub struct Pushgateway {
+    url: Url,
+    retries: u32,
+    backoff: Duration,
+    timeout: Duration,
+    flushed: AtomicBool,
+}
+
+impl Pushgateway {
+    /// Push the current metrics, replacing the ones previously pushed with the same grouping key.
+    ///
+    /// Failed pushes are retried with exponential backoff, so this can block for a while
+    /// if the Pushgateway is unavailable.
+    pub fn push(&self) -> Result<(), PushgatewayError> {
+        self.push_with(self.retries, self.timeout)
+    }
+
+    fn push_with(&self, mut retries: u32, timeout: Duration) -> Result<(), PushgatewayError> {
+        let metrics = encode_global_metrics().map_err(PushgatewayError::Encode)?;
+
+        let mut backoff = self.backoff;
+        loop {
+            let err = match http_client::send(
+                "PUT",
+                &self.url,
+                CONTENT_TYPE,
+                metrics.as_bytes(),
+                timeout,
+            ) {
+                Ok(status) if (200..300).contains(&status) => return Ok(()),
+                Ok(status) => PushgatewayError::UnexpectedStatus(status),
+                Err(err) => PushgatewayError::Io(err),
+            };
+
+            // Client errors, such as metrics that the Pushgateway rejects, fail the same way every time
+            let should_retry = match err {
+                PushgatewayError::UnexpectedStatus(status) => status >= 500 || status == 429,
+                _ => true,
+            };
+            if retries == 0 || !should_retry {
+                return Err(err);
+            }
+            thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2);
+            retries -= 1;
+        }
+    }
+
+    /// Push the metrics one last time before the process exits.
+    ///
+    /// This must be called to make sure that the final metrics are pushed and to find out
+    /// whether that failed. After this is called, the metrics are no longer pushed
+    /// when the client is dropped.
+    pub fn flush(&self) -> Result<(), PushgatewayError> {
+        self.flushed.store(true, Ordering::Release);
+        self.push()
+    }
+}
+
+impl Drop for Pushgateway {
+    fn drop(&mut self) {
+        if !self.flushed.load(Ordering::Acquire) && !thread::panicking() {
+            let _ = self.push_with(0, DROP_TIMEOUT);
+        }
+    }
+}
+
+/// Format one label of the grouping key as a path segment.
+///
+/// Values that cannot be used in the path as is, like ones that contain a slash,
+/// are encoded with URL-safe base64, which the Pushgateway indicates with the `@base64` suffix.
+fn path_segment(name: &str, value: &str) -> String {
+    let is_safe = !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte));
+    if is_safe {
+        format!("{name}/{value}")
+    } else if value.is_empty() {
+        // The Pushgateway expects a single padding character for empty values
+        format!("{name}@base64/=")
+    } else {
+        format!("{name}@base64/{}", base64_url(value.as_bytes()))
+    }
+}
+
+/// Encode the bytes with the URL-safe base64 alphabet from RFC 4648, including the padding.
+///
+/// This is implemented here, rather than with the `base64` crate, to keep the `pushgateway` feature
+/// free of additional dependencies.
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | (u32::from(*byte) << (16 - 8 * i))
+        });
+        // Each byte of input produces at least one more character of output
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+        for _ in chunk.len()..3 {
+            encoded.push('=');
+        }
+    }
+    encoded
+}
+
+/// An error that occurred while pushing the metrics to the Pushgateway
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PushgatewayError {
+    /// The Pushgateway URL is not a valid `http://` URL, or an `https://` URL without the `tls` feature
+    InvalidUrl(String),
+    /// The metrics could not be encoded
+    Encode(prometheus::Error),
+    /// The Pushgateway could not be reached
+    Io(io::Error),
+    /// The Pushgateway responded with a status code other than 2xx
+    UnexpectedStatus(u16),
+}
+
+impl fmt::Display for PushgatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(err) => write!(f, "invalid Pushgateway URL: {err}"),
+            Self::Encode(err) => write!(f, "failed to encode the metrics: {err}"),
+            Self::Io(err) => write!(f, "failed to push the metrics: {err}"),
+            Self::UnexpectedStatus(status) => {
+                write!(f, "the Pushgateway responded with status {status}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PushgatewayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Encode(err) => Some(err),
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
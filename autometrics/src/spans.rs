@@ -0,0 +1,54 @@
+use crate::{constants::RESULT_KEY, labels::Label};
+use std::fmt::Display;
+use tracing::{field, Span};
+
+/// Record the `result` label of the function call on its span
+pub fn record_span_result(span: &Span, counter_labels: &[Label]) {
+    if let Some((_, result)) = counter_labels.iter().find(|(key, _)| *key == RESULT_KEY) {
+        span.record(RESULT_KEY, result);
+    }
+}
+
+// These traits use the same trick as the ones in the `labels` module to record the
+// error only if the function returned a `Result` whose error type implements `Display`.
+// The error is first taken out of the `Result`, and then wrapped in a `SpanError`, so that the
+// `Display` bound is on the type being checked rather than nested in it. Otherwise, the compiler
+// would pick the `Result` implementation without checking the bound and fail to compile.
+
+pub trait GetSpanError {
+    type Error;
+    fn __autometrics_span_error(&self) -> Option<&Self::Error>;
+}
+
+impl<T, E> GetSpanError for Result<T, E> {
+    type Error = E;
+    fn __autometrics_span_error(&self) -> Option<&E> {
+        self.as_ref().err()
+    }
+}
+
+pub trait SkipGetSpanError {
+    fn __autometrics_span_error(&self) -> Option<&()> {
+        None
+    }
+}
+
+impl<T> SkipGetSpanError for &T {}
+
+pub struct SpanError<'a, E>(pub &'a E);
+
+pub trait RecordSpanError {
+    fn __autometrics_record_span_error(&self, span: &Span);
+}
+
+impl<E: Display> RecordSpanError for SpanError<'_, E> {
+    fn __autometrics_record_span_error(&self, span: &Span) {
+        span.record("error", field::display(self.0));
+    }
+}
+
+pub trait SkipSpanError {
+    fn __autometrics_record_span_error(&self, _span: &Span) {}
+}
+
+impl<E> SkipSpanError for &SpanError<'_, E> {}
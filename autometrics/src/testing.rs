@@ -0,0 +1,297 @@
+//! Utilities for testing code that is instrumented with autometrics.
+//!
+//! A [`TestRecorder`] captures the calls to instrumented functions made while it is installed,
+//! independently of the metrics libraries and of other tests running at the same time:
+//!
+//! ```rust
+//! use autometrics::{autometrics, assert_called, testing::TestRecorder};
+//!
+//! #[autometrics]
+//! fn add(a: i32, b: i32) -> Result<i32, ()> {
+//!     Ok(a + b)
+//! }
+//!
+//! let recorder = TestRecorder::install();
+//! add(1, 2).unwrap();
+//!
+//! assert_called!(add, times = 1, result = "ok");
+//! assert_eq!(recorder.calls()[0].function(), "add");
+//! ```
+//!
+//! Enable the `testing` feature only in your `[dev-dependencies]`. With Cargo's feature resolver
+//! version 2, it is then not enabled when building your application, so the instrumented functions
+//! do not check for a recorder. Workspaces without a root package need to set `resolver = "2"`.
+
+use crate::{constants::RESULT_KEY, labels::Label};
+use std::cell::RefCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type Calls = Arc<Mutex<Vec<Call>>>;
+
+thread_local! {
+    static CURRENT_RECORDER: RefCell<Option<Calls>> = const { RefCell::new(None) };
+}
+
+/// A call to an instrumented function that was captured by a [`TestRecorder`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    function: &'static str,
+    module: &'static str,
+    caller: &'static str,
+    result: Option<&'static str>,
+    labels: Vec<Label>,
+    duration: Duration,
+}
+
+impl Call {
+    pub fn function(&self) -> &'static str {
+        self.function
+    }
+
+    pub fn module(&self) -> &'static str {
+        self.module
+    }
+
+    /// The instrumented function that called this function, or the empty string
+    pub fn caller(&self) -> &'static str {
+        self.caller
+    }
+
+    /// `ok`, `error`, `panic`, or `cancelled` for functions that return a `Result`,
+    /// or `panic` or `cancelled` for other functions that did not return normally
+    pub fn result(&self) -> Option<&'static str> {
+        self.result
+    }
+
+    /// All of the labels that the function call counter was recorded with
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+/// Captures the calls to instrumented functions on the current thread until it is dropped.
+///
+/// Tests run on separate threads, so each test only sees its own calls. Async tests need to
+/// use a single-threaded runtime (the default for `#[tokio::test]`) for all of their calls
+/// to be captured. Calls are still recorded by the metrics libraries as well.
+///
+/// If a recorder is installed while another one is active, the new one captures the calls
+/// until it is dropped, after which the previous one is used again.
+pub struct TestRecorder {
+    calls: Calls,
+    previous: Option<Calls>,
+    /// The recorder is tied to the thread it was installed on
+    _not_send: PhantomData<*const ()>,
+}
+
+impl TestRecorder {
+    /// Start capturing the calls made on the current thread
+    pub fn install() -> Self {
+        let calls = Calls::default();
+        let previous = CURRENT_RECORDER.with(|current| current.borrow_mut().replace(calls.clone()));
+        TestRecorder {
+            calls,
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// All of the calls captured so far, in the order in which they finished
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls
+            .lock()
+            .expect("autometrics test recorder lock poisoned")
+            .clone()
+    }
+
+    /// The captured calls to the given function
+    pub fn calls_to(&self, function: &str) -> Vec<Call> {
+        self.calls()
+            .into_iter()
+            .filter(|call| same_function(call.function, function))
+            .collect()
+    }
+
+    /// Forget the calls captured so far
+    pub fn clear(&self) {
+        self.calls
+            .lock()
+            .expect("autometrics test recorder lock poisoned")
+            .clear();
+    }
+}
+
+impl Drop for TestRecorder {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_RECORDER.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+impl fmt::Debug for TestRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestRecorder")
+            .field("calls", &self.calls())
+            .finish()
+    }
+}
+
+/// Functions are compared without whitespace, because `stringify!` may add spaces around `::`
+fn same_function(recorded: &str, expected: &str) -> bool {
+    recorded
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .eq(expected.chars().filter(|c| !c.is_whitespace()))
+}
+
+/// Captures a single function call, if a recorder was installed when the call started
+pub(crate) struct RecordingTracker {
+    calls: Calls,
+    start: Instant,
+}
+
+impl RecordingTracker {
+    pub(crate) fn start() -> Option<Self> {
+        let calls = CURRENT_RECORDER.with(|current| current.borrow().clone())?;
+        Some(RecordingTracker {
+            calls,
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn finish(
+        self,
+        function: &'static str,
+        module: &'static str,
+        caller: &'static str,
+        counter_labels: &[Label],
+    ) {
+        let call = Call {
+            function,
+            module,
+            caller,
+            result: counter_labels
+                .iter()
+                .find(|(key, _)| *key == RESULT_KEY)
+                .map(|(_, value)| *value),
+            labels: counter_labels.to_vec(),
+            duration: self.start.elapsed(),
+        };
+        // Don't panic while the instrumented function is already panicking
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(call);
+        }
+    }
+}
+
+/// The expectations checked by [`assert_called!`]
+#[doc(hidden)]
+pub struct CallAssertion {
+    function: &'static str,
+    times: Option<usize>,
+    result: Option<&'static str>,
+    caller: Option<&'static str>,
+    module: Option<&'static str>,
+}
+
+impl CallAssertion {
+    pub fn new(function: &'static str) -> Self {
+        CallAssertion {
+            function,
+            times: None,
+            result: None,
+            caller: None,
+            module: None,
+        }
+    }
+
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    pub fn result(mut self, result: &'static str) -> Self {
+        self.result = Some(result);
+        self
+    }
+
+    pub fn caller(mut self, caller: &'static str) -> Self {
+        self.caller = Some(caller);
+        self
+    }
+
+    pub fn module(mut self, module: &'static str) -> Self {
+        self.module = Some(module);
+        self
+    }
+
+    fn matches(&self, call: &Call) -> bool {
+        same_function(call.function, self.function)
+            && self.result.is_none_or(|result| call.result == Some(result))
+            && self.caller.is_none_or(|caller| call.caller == caller)
+            && self.module.is_none_or(|module| call.module == module)
+    }
+
+    #[track_caller]
+    pub fn assert(self) {
+        let calls = CURRENT_RECORDER
+            .with(|current| current.borrow().clone())
+            .expect("assert_called! requires a TestRecorder to be installed on the current thread");
+        let calls = calls
+            .lock()
+            .expect("autometrics test recorder lock poisoned");
+        let matching = calls.iter().filter(|call| self.matches(call)).count();
+
+        let ok = match self.times {
+            Some(times) => matching == times,
+            None => matching > 0,
+        };
+        if !ok {
+            let expected = match self.times {
+                Some(times) => format!("{times}"),
+                None => "at least 1".to_string(),
+            };
+            panic!(
+                "expected {expected} matching call(s) to `{}` but found {matching}\n\
+                 result: {:?}, caller: {:?}, module: {:?}\n\
+                 recorded calls: {:#?}",
+                self.function, self.result, self.caller, self.module, *calls
+            );
+        }
+    }
+}
+
+/// Assert that an instrumented function was called while the current [`TestRecorder`] was installed.
+///
+/// The function can be followed by any of these expectations:
+/// - `times = 2` - the exact number of matching calls (by default, at least one call is expected)
+/// - `result = "ok"` - the `result` label of the calls
+/// - `caller = "handler"` - the instrumented function that made the calls
+/// - `module = "my_crate.db"` - the module label of the calls
+///
+/// ```rust,ignore
+/// assert_called!(add, times = 1, result = "ok");
+/// assert_called!(Db::get_user, caller = "handler");
+/// // Use a string for functions whose label is not a plain path
+/// assert_called!("<Db as Foo>::foo");
+/// ```
+#[macro_export]
+macro_rules! assert_called {
+    ($function:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::testing::CallAssertion::new($function)
+            $(.$key($value))*
+            .assert()
+    };
+    ($function:path $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::testing::CallAssertion::new(stringify!($function))
+            $(.$key($value))*
+            .assert()
+    };
+}
@@ -0,0 +1,109 @@
+//! Helpers for writing the Prometheus text format and the OpenMetrics text format, which share
+//! the syntax of their samples. See
+//! https://github.com/prometheus/docs/blob/main/content/docs/instrumenting/exposition_formats.md and
+//! https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use crate::constants::HISTOGRAM_NAME;
+use const_format::{concatcp, str_replace};
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+#[cfg(any(
+    feature = "native",
+    all(feature = "exemplars", feature = "opentelemetry")
+))]
+use std::time::{Duration, SystemTime};
+
+pub(crate) const HISTOGRAM_UNIT: &str = "seconds";
+
+/// OpenMetrics requires the name of a metric family with a unit to end with that unit,
+/// so the latency histogram is named with the unit in that format
+pub(crate) const HISTOGRAM_NAME_OPENMETRICS: &str =
+    concatcp!(str_replace!(HISTOGRAM_NAME, ".", "_"), "_", HISTOGRAM_UNIT);
+
+/// Write a sample with the given labels.
+///
+/// Empty label values are the same as a missing label, so they are left out.
+pub(crate) fn write_sample<'a>(
+    output: &mut String,
+    name: &str,
+    labels: impl IntoIterator<Item = (&'a str, &'a str)>,
+    value: impl fmt::Display,
+) -> fmt::Result {
+    output.push_str(name);
+
+    let mut labels = labels
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .peekable();
+    if labels.peek().is_some() {
+        output.push('{');
+        for (i, (key, value)) in labels.enumerate() {
+            if i > 0 {
+                output.push(',');
+            }
+            write!(output, "{key}=\"{}\"", escape(value, true))?;
+        }
+        output.push('}');
+    }
+
+    writeln!(output, " {value}")
+}
+
+pub(crate) fn format_float(value: f64) -> Cow<'static, str> {
+    if value == f64::INFINITY {
+        "+Inf".into()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".into()
+    } else if value.is_nan() {
+        "NaN".into()
+    } else {
+        format!("{value:?}").into()
+    }
+}
+
+/// Escape backslashes and newlines, and double quotes in label values
+/// (and in the help text of the OpenMetrics format)
+pub(crate) fn escape(value: &str, escape_quotes: bool) -> Cow<'_, str> {
+    if !value.contains(['\\', '\n', '"']) {
+        return value.into();
+    }
+
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if escape_quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped.into()
+}
+
+// The timestamps are only used by the native metrics and the exemplars
+#[cfg(any(
+    feature = "native",
+    all(feature = "exemplars", feature = "opentelemetry")
+))]
+pub(crate) fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Formats a time since the Unix epoch as seconds with millisecond precision
+#[cfg(any(
+    feature = "native",
+    all(feature = "exemplars", feature = "opentelemetry")
+))]
+pub(crate) struct Timestamp(pub(crate) Duration);
+
+#[cfg(any(
+    feature = "native",
+    all(feature = "exemplars", feature = "opentelemetry")
+))]
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03}", self.0.as_secs(), self.0.subsec_millis())
+    }
+}
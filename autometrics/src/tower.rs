@@ -0,0 +1,278 @@
+//! Middleware for instrumenting HTTP services built with [`tower`](https://docs.rs/tower),
+//! such as `axum`, `tonic` or `hyper` servers.
+//!
+//! The [`AutometricsLayer`] records the same metrics as the `autometrics` macro for every request,
+//! using the route as the `function` label. This means the generated queries and alerts work for
+//! the routes just like for instrumented functions.
+//!
+//! The layer needs to be told how to find the route that matched the request, because that is
+//! up to the router. For example, with `axum`:
+//!
+//! ```rust,ignore
+//! use autometrics::tower::AutometricsLayer;
+//! use axum::{extract::MatchedPath, routing::get, Router};
+//!
+//! let app = Router::new()
+//!     .route("/users/:id", get(get_user))
+//!     .route_layer(AutometricsLayer::new(|request: &Request<_>| {
+//!         // Use the route pattern rather than the path to avoid one time series per user ID
+//!         request
+//!             .extensions()
+//!             .get::<MatchedPath>()
+//!             .map(|path| path.as_str().to_string())
+//!             .unwrap_or_default()
+//!     }));
+//! ```
+
+use crate::{
+    __private::CALLER,
+    caller::current_caller,
+    constants::{ERROR_KEY, FUNCTION_KEY, OK_KEY},
+    labels::{create_label_array, intern_label_value},
+    task_local::TaskLocalFuture,
+    tracker::{AutometricsTracker, TrackMetrics},
+};
+use http::{Request, Response};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// The `module` label used for requests unless configured otherwise
+const DEFAULT_MODULE: &str = "http";
+
+/// A [`Layer`] that tracks the number, duration, and result of requests to the wrapped service.
+///
+/// By default, any response with a `5xx` status code, as well as any error returned by the service,
+/// counts as an error.
+#[derive(Clone, Debug)]
+pub struct AutometricsLayer<R, C = ServerErrors> {
+    module: &'static str,
+    route: R,
+    classifier: C,
+    track_concurrency: bool,
+}
+
+impl<F> AutometricsLayer<RouteFn<F>> {
+    /// Use the given function to determine the route name used as the `function` label.
+    ///
+    /// The route names are interned, so the function should return one of a limited set of values,
+    /// such as the route pattern matched by the router, rather than the request path.
+    /// Only the first 1000 distinct route names are recorded; all others are grouped under `__autometrics_other`.
+    pub fn new(route: F) -> Self {
+        AutometricsLayer {
+            module: DEFAULT_MODULE,
+            route: RouteFn(route),
+            classifier: ServerErrors,
+            track_concurrency: false,
+        }
+    }
+}
+
+impl<R, C> AutometricsLayer<R, C> {
+    /// Set the `module` label for the requests (defaults to `http`).
+    pub fn module(mut self, module: &'static str) -> Self {
+        self.module = module;
+        self
+    }
+
+    /// Track the number of requests currently being handled,
+    /// like the `track_concurrency` argument of the `autometrics` macro.
+    pub fn track_concurrency(mut self) -> Self {
+        self.track_concurrency = true;
+        self
+    }
+
+    /// Count only the responses for which the given function returns `true` as successful,
+    /// like the `ok_if` argument of the `autometrics` macro.
+    pub fn ok_if<F>(self, ok_if: F) -> AutometricsLayer<R, OkIf<F>> {
+        AutometricsLayer {
+            module: self.module,
+            route: self.route,
+            classifier: OkIf(ok_if),
+            track_concurrency: self.track_concurrency,
+        }
+    }
+
+    /// Count the responses for which the given function returns `true` as errors,
+    /// like the `error_if` argument of the `autometrics` macro.
+    pub fn error_if<F>(self, error_if: F) -> AutometricsLayer<R, ErrorIf<F>> {
+        AutometricsLayer {
+            module: self.module,
+            route: self.route,
+            classifier: ErrorIf(error_if),
+            track_concurrency: self.track_concurrency,
+        }
+    }
+}
+
+impl<S, R: Clone, C: Clone> Layer<S> for AutometricsLayer<R, C> {
+    type Service = AutometricsService<S, R, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AutometricsService {
+            inner,
+            module: self.module,
+            route: self.route.clone(),
+            classifier: self.classifier.clone(),
+            track_concurrency: self.track_concurrency,
+        }
+    }
+}
+
+/// Determines the route name used as the `function` label for a request
+pub trait RouteName<B> {
+    fn route_name(&self, request: &Request<B>) -> &'static str;
+}
+
+/// Uses a function to determine the route name, see [`AutometricsLayer::new`]
+#[derive(Clone, Copy, Debug)]
+pub struct RouteFn<F>(F);
+
+impl<B, F, S> RouteName<B> for RouteFn<F>
+where
+    F: Fn(&Request<B>) -> S,
+    S: AsRef<str>,
+{
+    fn route_name(&self, request: &Request<B>) -> &'static str {
+        intern_label_value(FUNCTION_KEY, (self.0)(request))
+    }
+}
+
+/// Determines whether a response should be counted as an error
+pub trait ClassifyResponse<B> {
+    fn is_error(&self, response: &Response<B>) -> bool;
+}
+
+/// Counts responses with a `5xx` status code as errors
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerErrors;
+
+impl<B> ClassifyResponse<B> for ServerErrors {
+    fn is_error(&self, response: &Response<B>) -> bool {
+        response.status().is_server_error()
+    }
+}
+
+/// See [`AutometricsLayer::ok_if`]
+#[derive(Clone, Copy, Debug)]
+pub struct OkIf<F>(F);
+
+impl<B, F> ClassifyResponse<B> for OkIf<F>
+where
+    F: Fn(&Response<B>) -> bool,
+{
+    fn is_error(&self, response: &Response<B>) -> bool {
+        !(self.0)(response)
+    }
+}
+
+/// See [`AutometricsLayer::error_if`]
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorIf<F>(F);
+
+impl<B, F> ClassifyResponse<B> for ErrorIf<F>
+where
+    F: Fn(&Response<B>) -> bool,
+{
+    fn is_error(&self, response: &Response<B>) -> bool {
+        (self.0)(response)
+    }
+}
+
+/// The service created by the [`AutometricsLayer`]
+#[derive(Clone, Debug)]
+pub struct AutometricsService<S, R, C> {
+    inner: S,
+    module: &'static str,
+    route: R,
+    classifier: C,
+    track_concurrency: bool,
+}
+
+impl<S, R, C, ReqBody, ResBody> Service<Request<ReqBody>> for AutometricsService<S, R, C>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    R: RouteName<ReqBody>,
+    C: ClassifyResponse<ResBody> + Clone,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future, C>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let function = self.route.route_name(&request);
+        let caller = current_caller();
+        let tracker = AutometricsTracker::start(
+            function,
+            self.module,
+            &[],
+            None,
+            None,
+            self.track_concurrency,
+        );
+
+        // Instrumented functions called while handling the request report the route as their caller
+        let inner = &mut self.inner;
+        let future = CALLER.sync_scope(function, || inner.call(request));
+
+        ResponseFuture {
+            future: CALLER.scope(function, future),
+            tracker: Some(tracker),
+            classifier: self.classifier.clone(),
+            caller,
+        }
+    }
+}
+
+pin_project! {
+    /// The response future of the [`AutometricsService`]
+    pub struct ResponseFuture<F, C> {
+        #[pin]
+        future: TaskLocalFuture<&'static str, F>,
+        // If the future is dropped before it completes, the tracker records the request as cancelled
+        tracker: Option<AutometricsTracker>,
+        classifier: C,
+        caller: &'static str,
+    }
+}
+
+impl<F, C, ResBody, E> Future for ResponseFuture<F, C>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+    C: ClassifyResponse<ResBody>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let output = match this.future.poll(cx) {
+            Poll::Ready(output) => output,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        if let Some(tracker) = this.tracker.take() {
+            let result = match &output {
+                Ok(response) if !this.classifier.is_error(response) => OK_KEY,
+                _ => ERROR_KEY,
+            };
+            let counter_labels = create_label_array(
+                result,
+                tracker.function(),
+                tracker.module(),
+                this.caller,
+                None,
+                tracker.labels(),
+            );
+            tracker.finish(&counter_labels);
+        }
+
+        Poll::Ready(output)
+    }
+}
@@ -0,0 +1,173 @@
+//! A [`tracing_subscriber`](https://docs.rs/tracing-subscriber) layer that records
+//! autometrics for code that is instrumented with [`tracing`](https://docs.rs/tracing) spans.
+//!
+//! The [`AutometricsLayer`] treats every span as a function call that starts when the span
+//! is entered for the first time and finishes when the span is closed. This makes it possible
+//! to get the same metrics, queries, and alerts for functions annotated with `#[tracing::instrument]`,
+//! including those in crates that you cannot add `#[autometrics]` to.
+//!
+//! ```rust,ignore
+//! use autometrics::tracing_subscriber::AutometricsLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! tracing_subscriber::registry()
+//!     .with(tracing_subscriber::fmt::layer())
+//!     .with(AutometricsLayer::new())
+//!     .init();
+//! ```
+
+use crate::{
+    constants::{ERROR_KEY, MODULE_KEY, OK_KEY},
+    labels::{create_label_array, intern_label_value},
+    tracker::{AutometricsTracker, TrackMetrics},
+};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Metadata, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// The name of the span field that marks a call as an error
+const ERROR_FIELD: &str = "error";
+
+/// A [`Layer`] that records the calls, errors, and latency of every span.
+///
+/// The spans are labeled as follows:
+/// - `function` - the name of the span
+/// - `module` - the target of the span (by default, the module path where it was created), with `::` replaced by `.`
+/// - `caller` - the name of the parent span, if there is one
+/// - `result` - `error` if a value was recorded for the span's `error` field, otherwise `ok`
+///
+/// Spans created by functions with the `autometrics` macro (when the `tracing` feature is enabled)
+/// are skipped, because those calls are already recorded by the macro.
+///
+/// Use the filtering provided by `tracing_subscriber`, such as [`Layer::with_filter`],
+/// to limit which spans are recorded.
+#[derive(Clone, Debug, Default)]
+pub struct AutometricsLayer {
+    _private: (),
+}
+
+impl AutometricsLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// The call tracked for a single span, stored in the span's extensions
+struct SpanCall {
+    function: &'static str,
+    module: &'static str,
+    caller: &'static str,
+    error: bool,
+    tracker: Option<AutometricsTracker>,
+}
+
+impl<S> Layer<S> for AutometricsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let metadata = attrs.metadata();
+        if is_autometrics_span(metadata) {
+            return;
+        }
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let mut call = SpanCall {
+            function: metadata.name(),
+            module: module_label(metadata.target()),
+            caller: span.parent().map(|parent| parent.name()).unwrap_or(""),
+            error: false,
+            tracker: None,
+        };
+        attrs.record(&mut ErrorVisitor(&mut call.error));
+        span.extensions_mut().insert(call);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(call) = span.extensions_mut().get_mut::<SpanCall>() {
+                values.record(&mut ErrorVisitor(&mut call.error));
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(call) = span.extensions_mut().get_mut::<SpanCall>() {
+                // Spans of async functions are entered every time the future is polled,
+                // so the call starts the first time the span is entered
+                if call.tracker.is_none() {
+                    call.tracker = Some(AutometricsTracker::start_with_caller(
+                        call.caller,
+                        call.function,
+                        call.module,
+                        &[],
+                        None,
+                        None,
+                        false,
+                    ));
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let call = span.extensions_mut().remove::<SpanCall>();
+        let call = match call {
+            Some(call) => call,
+            None => return,
+        };
+
+        // Spans that were never entered do not count as calls
+        if let Some(tracker) = call.tracker {
+            let result = if call.error { ERROR_KEY } else { OK_KEY };
+            let counter_labels =
+                create_label_array(result, call.function, call.module, call.caller, None, &[]);
+            tracker.finish(&counter_labels);
+        }
+    }
+}
+
+/// The spans created by the `autometrics` macro have these fields
+fn is_autometrics_span(metadata: &Metadata<'_>) -> bool {
+    let fields = metadata.fields();
+    ["function", "module", "caller", "result"]
+        .iter()
+        .all(|name| fields.field(name).is_some())
+}
+
+/// Format the span target like the `module` label of instrumented functions
+fn module_label(target: &'static str) -> &'static str {
+    if target.contains("::") {
+        intern_label_value(MODULE_KEY, target.replace("::", "."))
+    } else {
+        target
+    }
+}
+
+/// Sets the flag if a value is recorded for the `error` field,
+/// unless the value is `false`
+struct ErrorVisitor<'a>(&'a mut bool);
+
+impl Visit for ErrorVisitor<'_> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == ERROR_FIELD {
+            *self.0 = value;
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, _value: &dyn fmt::Debug) {
+        if field.name() == ERROR_FIELD {
+            *self.0 = true;
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::labels::Label;
+use once_cell::sync::OnceCell;
+use smallvec::SmallVec;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Identifies a set of labels by the addresses and lengths of their keys and values.
+///
+/// All label keys and values are `&'static str`s that are either string literals or interned,
+/// so comparing the addresses is much cheaper than comparing the strings and gives the same result
+/// in nearly all cases. Two equal strings stored at different addresses only result in a second
+/// cache entry for the same metric.
+#[derive(PartialEq, Eq, Hash)]
+struct LabelsKey(SmallVec<[(usize, usize); 16]>);
+
+impl LabelsKey {
+    fn new(labels: &[Label]) -> Self {
+        LabelsKey(
+            labels
+                .iter()
+                .flat_map(|(key, value)| [*key, *value])
+                .map(|s| (s.as_ptr() as usize, s.len()))
+                .collect(),
+        )
+    }
+}
+
+/// Caches the metric handles resolved for each set of labels, so that
+/// looking up the labels in the metrics library only happens once.
+pub(crate) struct HandleCache<T> {
+    handles: OnceCell<RwLock<HashMap<LabelsKey, T>>>,
+}
+
+impl<T: Clone> HandleCache<T> {
+    pub(crate) const fn new() -> Self {
+        HandleCache {
+            handles: OnceCell::new(),
+        }
+    }
+
+    pub(crate) fn get_or_insert_with(&self, labels: &[Label], create: impl FnOnce() -> T) -> T {
+        let key = LabelsKey::new(labels);
+        let handles = self.handles.get_or_init(Default::default);
+        if let Some(handle) = handles
+            .read()
+            .expect("autometrics handle cache lock poisoned")
+            .get(&key)
+        {
+            return handle.clone();
+        }
+
+        let handle = create();
+        handles
+            .write()
+            .expect("autometrics handle cache lock poisoned")
+            .entry(key)
+            .or_insert(handle)
+            .clone()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn clear(&self) {
+        if let Some(handles) = self.handles.get() {
+            handles
+                .write()
+                .expect("autometrics handle cache lock poisoned")
+                .clear();
+        }
+    }
+}
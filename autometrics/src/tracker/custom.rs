@@ -0,0 +1,166 @@
+use crate::labels::{CustomLabels, Label};
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Whether a global tracker is set, so that the lock doesn't need to be taken for every call otherwise
+static HAS_GLOBAL_TRACKER: AtomicBool = AtomicBool::new(false);
+static GLOBAL_TRACKER: Lazy<RwLock<Option<Arc<dyn Tracker>>>> = Lazy::new(|| RwLock::new(None));
+
+/// A metrics library that autometrics records function calls with.
+///
+/// Implement this trait and register it using [`set_global_tracker`] to use a metrics
+/// library that autometrics does not support out of the box.
+pub trait Tracker: Send + Sync {
+    /// Called when an instrumented function starts.
+    ///
+    /// If [`FunctionCall::track_concurrency`] is `true`, this should increase the number of concurrent calls.
+    fn start(&self, call: &FunctionCall<'_>) {
+        let _ = call;
+    }
+
+    /// Called when an instrumented function returns, panics, or (for async functions) is cancelled.
+    ///
+    /// The `counter_labels` contain the `function`, `module`, `caller`, and `result` labels,
+    /// the `ok` or `error` label if the return value has one, and any custom labels.
+    /// If [`FunctionCall::track_concurrency`] is `true`, this should decrease the number of concurrent calls.
+    fn finish(&self, call: &FunctionCall<'_>, counter_labels: &[Label], duration: Duration);
+}
+
+/// A call to an instrumented function, passed to the [`Tracker`]
+#[derive(Clone, Copy, Debug)]
+pub struct FunctionCall<'a> {
+    function: &'static str,
+    module: &'static str,
+    caller: &'static str,
+    labels: &'a [Label],
+    buckets: Option<&'static [f64]>,
+    track_concurrency: bool,
+}
+
+impl<'a> FunctionCall<'a> {
+    pub fn function(&self) -> &'static str {
+        self.function
+    }
+
+    pub fn module(&self) -> &'static str {
+        self.module
+    }
+
+    /// The instrumented function that called this function, or the empty string
+    pub fn caller(&self) -> &'static str {
+        self.caller
+    }
+
+    /// The custom labels of the function, which should be attached to all of its metrics
+    pub fn labels(&self) -> &'a [Label] {
+        self.labels
+    }
+
+    /// The histogram buckets configured for the function, if they differ from the default
+    pub fn buckets(&self) -> Option<&'static [f64]> {
+        self.buckets
+    }
+
+    pub fn track_concurrency(&self) -> bool {
+        self.track_concurrency
+    }
+}
+
+/// Record all function calls with the given tracker instead of the
+/// metrics libraries selected by the feature flags.
+///
+/// This replaces any tracker that was set before. Calls that are already in progress
+/// are finished with the tracker they were started with.
+pub fn set_global_tracker(tracker: Box<dyn Tracker>) {
+    *GLOBAL_TRACKER
+        .write()
+        .expect("autometrics global tracker lock poisoned") = Some(Arc::from(tracker));
+    HAS_GLOBAL_TRACKER.store(true, Ordering::Release);
+}
+
+/// Remove the tracker set using [`set_global_tracker`], so function calls are
+/// recorded with the metrics libraries selected by the feature flags again.
+pub fn reset_global_tracker() {
+    HAS_GLOBAL_TRACKER.store(false, Ordering::Release);
+    *GLOBAL_TRACKER
+        .write()
+        .expect("autometrics global tracker lock poisoned") = None;
+}
+
+fn global_tracker() -> Option<Arc<dyn Tracker>> {
+    if !HAS_GLOBAL_TRACKER.load(Ordering::Acquire) {
+        return None;
+    }
+
+    GLOBAL_TRACKER
+        .read()
+        .expect("autometrics global tracker lock poisoned")
+        .clone()
+}
+
+/// Tracks a single function call using the global tracker
+pub(super) struct CustomTracker {
+    tracker: Arc<dyn Tracker>,
+    function: &'static str,
+    module: &'static str,
+    caller: &'static str,
+    labels: CustomLabels,
+    buckets: Option<&'static [f64]>,
+    track_concurrency: bool,
+    start: Instant,
+}
+
+impl CustomTracker {
+    /// Start tracking the call if a global tracker is set
+    pub(super) fn start(
+        function: &'static str,
+        module: &'static str,
+        caller: &'static str,
+        labels: &[Label],
+        buckets: Option<&'static [f64]>,
+        track_concurrency: bool,
+    ) -> Option<Self> {
+        let tracker = Self {
+            tracker: global_tracker()?,
+            function,
+            module,
+            caller,
+            labels: CustomLabels::from_slice(labels),
+            buckets,
+            track_concurrency,
+            start: Instant::now(),
+        };
+        tracker.tracker.start(&tracker.call());
+        Some(tracker)
+    }
+
+    pub(super) fn function(&self) -> &'static str {
+        self.function
+    }
+
+    pub(super) fn module(&self) -> &'static str {
+        self.module
+    }
+
+    pub(super) fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    pub(super) fn finish(self, counter_labels: &[Label]) {
+        let duration = self.start.elapsed();
+        self.tracker.finish(&self.call(), counter_labels, duration);
+    }
+
+    fn call(&self) -> FunctionCall<'_> {
+        FunctionCall {
+            function: self.function,
+            module: self.module,
+            caller: self.caller,
+            labels: &self.labels,
+            buckets: self.buckets,
+            track_concurrency: self.track_concurrency,
+        }
+    }
+}
@@ -1,9 +1,18 @@
-use crate::{constants::*, labels::Label, tracker::TrackMetrics};
+use crate::{
+    constants::*,
+    labels::{CustomLabels, Label},
+    tracker::{cache::HandleCache, CallSite, TrackMetrics},
+};
 use metrics::{
     describe_counter, describe_gauge, describe_histogram, register_counter, register_gauge,
-    register_histogram, Gauge,
+    register_histogram, Counter, Gauge, Histogram, Recorder,
+};
+use smallvec::SmallVec;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Once,
+    time::Instant,
 };
-use std::{sync::Once, time::Instant};
 
 static ONCE: Once = Once::new();
 
@@ -15,13 +24,64 @@ fn describe_metrics() {
     });
 }
 
+/// The address of the global recorder, used to tell if it changed
+fn recorder_address() -> usize {
+    metrics::recorder() as *const dyn Recorder as *const () as usize
+}
+
+/// Caches the metric handles of a single instrumented function, see [`CallSite`]
+pub(crate) struct CallSiteCache {
+    /// Handles are registered with the recorder that is installed at the time, so the cache
+    /// needs to be cleared if the functions were called before the recorder was installed
+    recorder: AtomicUsize,
+    histograms: HandleCache<Histogram>,
+    gauges: HandleCache<Gauge>,
+    counters: HandleCache<Counter>,
+}
+
+impl CallSiteCache {
+    pub(crate) const fn new() -> Self {
+        CallSiteCache {
+            recorder: AtomicUsize::new(0),
+            histograms: HandleCache::new(),
+            gauges: HandleCache::new(),
+            counters: HandleCache::new(),
+        }
+    }
+
+    fn check_recorder(&self) {
+        let recorder = recorder_address();
+        if self.recorder.load(Ordering::Acquire) != recorder {
+            self.recorder.store(recorder, Ordering::Release);
+            self.histograms.clear();
+            self.gauges.clear();
+            self.counters.clear();
+        }
+    }
+}
+
 pub struct MetricsTracker {
     module: &'static str,
     function: &'static str,
+    labels: CustomLabels,
+    histogram: Histogram,
     gauge: Option<Gauge>,
+    cache: Option<&'static CallSiteCache>,
     start: Instant,
 }
 
+/// The labels for the histogram and gauge: the function, module, and any custom labels
+fn function_and_module_labels(
+    function: &'static str,
+    module: &'static str,
+    labels: &[Label],
+) -> SmallVec<[Label; 8]> {
+    [(FUNCTION_KEY, function), (MODULE_KEY, module)]
+        .into_iter()
+        .chain(labels.iter().copied())
+        .collect()
+}
+
 impl TrackMetrics for MetricsTracker {
     fn function(&self) -> &'static str {
         self.function
@@ -31,11 +91,53 @@ impl TrackMetrics for MetricsTracker {
         self.module
     }
 
-    fn start(function: &'static str, module: &'static str, track_concurrency: bool) -> Self {
+    fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    fn start(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        _buckets: Option<&'static [f64]>,
+        call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self {
+        // The histogram buckets cannot be set per time series with this library,
+        // so they are configured by the exporter instead
         describe_metrics();
 
+        let cache = call_site.map(|call_site| &call_site.metrics);
+        if let Some(cache) = cache {
+            cache.check_recorder();
+        }
+
+        // The function and module are the same for every call from the same call site,
+        // so only the custom labels need to be part of the cache key
+        let create_histogram = || {
+            register_histogram!(
+                HISTOGRAM_NAME,
+                function_and_module_labels(function, module, labels).as_slice()
+            )
+        };
+        let histogram = match cache {
+            Some(cache) => cache
+                .histograms
+                .get_or_insert_with(labels, create_histogram),
+            None => create_histogram(),
+        };
+
         let gauge = if track_concurrency {
-            let gauge = register_gauge!(GAUGE_NAME, "function" => function, "module" => module);
+            let create_gauge = || {
+                register_gauge!(
+                    GAUGE_NAME,
+                    function_and_module_labels(function, module, labels).as_slice()
+                )
+            };
+            let gauge = match cache {
+                Some(cache) => cache.gauges.get_or_insert_with(labels, create_gauge),
+                None => create_gauge(),
+            };
             gauge.increment(1.0);
             Some(gauge)
         } else {
@@ -45,16 +147,27 @@ impl TrackMetrics for MetricsTracker {
         Self {
             module,
             function,
+            labels: CustomLabels::from_slice(labels),
+            histogram,
             gauge,
+            cache,
             start: Instant::now(),
         }
     }
 
-    fn finish<'a>(self, counter_labels: &[Label]) {
+    fn finish(self, counter_labels: &[Label]) {
         let duration = self.start.elapsed().as_secs_f64();
-        register_counter!(COUNTER_NAME, counter_labels).increment(1);
-        register_histogram!(HISTOGRAM_NAME, "function" => self.function, "module" => self.module)
-            .record(duration);
+
+        let create_counter = || register_counter!(COUNTER_NAME, counter_labels);
+        let counter = match self.cache {
+            Some(cache) => cache
+                .counters
+                .get_or_insert_with(counter_labels, create_counter),
+            None => create_counter(),
+        };
+        counter.increment(1);
+
+        self.histogram.record(duration);
         if let Some(gauge) = self.gauge {
             gauge.decrement(1.0);
         }
@@ -1,28 +1,339 @@
-use crate::labels::Label;
+use crate::{
+    caller::current_caller,
+    constants::{CANCELLED_KEY, PANIC_KEY},
+    labels::{create_label_array, CustomLabels, Label},
+};
+use std::thread;
 
+#[cfg(any(feature = "metrics", feature = "native", feature = "prometheus"))]
+mod cache;
+mod custom;
 #[cfg(feature = "metrics")]
 mod metrics;
+#[cfg(feature = "native")]
+pub(crate) mod native;
 #[cfg(feature = "opentelemetry")]
-mod opentelemetry;
+pub(crate) mod opentelemetry;
 #[cfg(feature = "prometheus")]
-mod prometheus;
-
-// By default, use the opentelemetry crate
-#[cfg(all(
-    feature = "opentelemetry",
-    not(any(feature = "metrics", feature = "prometheus"))
-))]
-pub use self::opentelemetry::OpenTelemetryTracker as AutometricsTracker;
-
-// But use one of the other crates if either of those features are enabled
-#[cfg(all(feature = "metrics", not(feature = "prometheus")))]
-pub use self::metrics::MetricsTracker as AutometricsTracker;
-#[cfg(feature = "prometheus")]
-pub use self::prometheus::PrometheusTracker as AutometricsTracker;
+pub(crate) mod prometheus;
+
+use self::custom::CustomTracker;
+pub use self::custom::{reset_global_tracker, set_global_tracker, FunctionCall, Tracker};
+#[cfg(feature = "native")]
+pub use self::native::{encode_native_metrics, encode_native_metrics_protobuf, TextFormat};
+#[cfg(feature = "opentelemetry")]
+pub use self::opentelemetry::set_meter_provider;
+#[cfg(feature = "testing")]
+use crate::testing::RecordingTracker;
 
 pub trait TrackMetrics {
     fn function(&self) -> &'static str;
     fn module(&self) -> &'static str;
-    fn start(function: &'static str, module: &'static str, track_concurrency: bool) -> Self;
-    fn finish<'a>(self, counter_labels: &[Label]);
+    fn labels(&self) -> &[Label];
+    fn start(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        buckets: Option<&'static [f64]>,
+        call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self;
+    fn finish(self, counter_labels: &[Label]);
+}
+
+/// Caches the metrics resolved for a single instrumented function.
+///
+/// The `autometrics` macro creates a static `CallSite` for every instrumented function,
+/// so that the label values only need to be looked up in the metrics library the first
+/// time the function is called with them. After that, recording a call only updates the
+/// cached metrics.
+pub struct CallSite {
+    #[cfg(feature = "metrics")]
+    metrics: self::metrics::CallSiteCache,
+    #[cfg(feature = "native")]
+    native: self::native::CallSiteCache,
+    #[cfg(feature = "prometheus")]
+    prometheus: self::prometheus::CallSiteCache,
+}
+
+impl CallSite {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        CallSite {
+            #[cfg(feature = "metrics")]
+            metrics: self::metrics::CallSiteCache::new(),
+            #[cfg(feature = "native")]
+            native: self::native::CallSiteCache::new(),
+            #[cfg(feature = "prometheus")]
+            prometheus: self::prometheus::CallSiteCache::new(),
+        }
+    }
+}
+
+/// Forwards each function call to every metrics library enabled by the feature flags.
+///
+/// This makes it possible to record the metrics with more than one library at the same time,
+/// for example while migrating from the `prometheus` crate to OpenTelemetry.
+/// If none of them are enabled, the calls are only recorded by the global tracker, if one is set.
+struct Backends {
+    function: &'static str,
+    module: &'static str,
+    labels: CustomLabels,
+    #[cfg(feature = "metrics")]
+    metrics: self::metrics::MetricsTracker,
+    #[cfg(feature = "native")]
+    native: self::native::NativeTracker,
+    #[cfg(feature = "opentelemetry")]
+    opentelemetry: self::opentelemetry::OpenTelemetryTracker,
+    #[cfg(feature = "prometheus")]
+    prometheus: self::prometheus::PrometheusTracker,
+}
+
+impl TrackMetrics for Backends {
+    fn function(&self) -> &'static str {
+        self.function
+    }
+
+    fn module(&self) -> &'static str {
+        self.module
+    }
+
+    fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    #[allow(unused_variables)]
+    fn start(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        buckets: Option<&'static [f64]>,
+        call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self {
+        Self {
+            function,
+            module,
+            labels: CustomLabels::from_slice(labels),
+            #[cfg(feature = "metrics")]
+            metrics: TrackMetrics::start(
+                function,
+                module,
+                labels,
+                buckets,
+                call_site,
+                track_concurrency,
+            ),
+            #[cfg(feature = "native")]
+            native: TrackMetrics::start(
+                function,
+                module,
+                labels,
+                buckets,
+                call_site,
+                track_concurrency,
+            ),
+            #[cfg(feature = "opentelemetry")]
+            opentelemetry: TrackMetrics::start(
+                function,
+                module,
+                labels,
+                buckets,
+                call_site,
+                track_concurrency,
+            ),
+            #[cfg(feature = "prometheus")]
+            prometheus: TrackMetrics::start(
+                function,
+                module,
+                labels,
+                buckets,
+                call_site,
+                track_concurrency,
+            ),
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn finish(self, counter_labels: &[Label]) {
+        #[cfg(feature = "metrics")]
+        self.metrics.finish(counter_labels);
+        #[cfg(feature = "native")]
+        self.native.finish(counter_labels);
+        #[cfg(feature = "opentelemetry")]
+        self.opentelemetry.finish(counter_labels);
+        #[cfg(feature = "prometheus")]
+        self.prometheus.finish(counter_labels);
+    }
+}
+
+/// Either the metrics libraries enabled by the feature flags or the custom global tracker
+enum Inner {
+    // Both are boxed because the size of the trackers depends on which metrics libraries are enabled
+    Backends(Box<Backends>),
+    Custom(Box<CustomTracker>),
+}
+
+impl Inner {
+    fn function(&self) -> &'static str {
+        match self {
+            Inner::Backends(backends) => backends.function(),
+            Inner::Custom(custom) => custom.function(),
+        }
+    }
+
+    fn module(&self) -> &'static str {
+        match self {
+            Inner::Backends(backends) => backends.module(),
+            Inner::Custom(custom) => custom.module(),
+        }
+    }
+
+    fn labels(&self) -> &[Label] {
+        match self {
+            Inner::Backends(backends) => backends.labels(),
+            Inner::Custom(custom) => custom.labels(),
+        }
+    }
+
+    fn finish(self, counter_labels: &[Label]) {
+        match self {
+            Inner::Backends(backends) => backends.finish(counter_labels),
+            Inner::Custom(custom) => custom.finish(counter_labels),
+        }
+    }
+}
+
+/// Tracks a single function call using all of the metrics libraries enabled by the feature flags,
+/// or using the tracker set with [`set_global_tracker`].
+///
+/// This acts as a drop guard: if the tracker is dropped before the call is finished, either
+/// the instrumented function panicked or the future returned by an async function was dropped
+/// before it completed. The call is then recorded with `result="panic"` or `result="cancelled"`,
+/// respectively. This also ensures that the concurrent calls gauge is always decremented.
+pub struct AutometricsTracker {
+    inner: Option<Inner>,
+    caller: &'static str,
+    #[cfg(feature = "testing")]
+    recording: Option<RecordingTracker>,
+}
+
+impl AutometricsTracker {
+    fn inner(&self) -> &Inner {
+        self.inner
+            .as_ref()
+            .expect("autometrics tracker used after it was finished")
+    }
+
+    /// Start tracking a call made by the given caller, rather than the caller of the current context
+    pub(crate) fn start_with_caller(
+        caller: &'static str,
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        buckets: Option<&'static [f64]>,
+        call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self {
+        let inner = match CustomTracker::start(
+            function,
+            module,
+            caller,
+            labels,
+            buckets,
+            track_concurrency,
+        ) {
+            Some(custom) => Inner::Custom(Box::new(custom)),
+            None => Inner::Backends(Box::new(Backends::start(
+                function,
+                module,
+                labels,
+                buckets,
+                call_site,
+                track_concurrency,
+            ))),
+        };
+
+        Self {
+            inner: Some(inner),
+            // Remember the caller in case the tracker is dropped outside of the caller's scope,
+            // for example when a future is dropped by the async runtime
+            caller,
+            #[cfg(feature = "testing")]
+            recording: RecordingTracker::start(),
+        }
+    }
+
+    fn finish_inner(&mut self, inner: Inner, counter_labels: &[Label]) {
+        #[cfg(feature = "testing")]
+        if let Some(recording) = self.recording.take() {
+            recording.finish(
+                inner.function(),
+                inner.module(),
+                self.caller,
+                counter_labels,
+            );
+        }
+        inner.finish(counter_labels);
+    }
+}
+
+impl TrackMetrics for AutometricsTracker {
+    fn function(&self) -> &'static str {
+        self.inner().function()
+    }
+
+    fn module(&self) -> &'static str {
+        self.inner().module()
+    }
+
+    fn labels(&self) -> &[Label] {
+        self.inner().labels()
+    }
+
+    fn start(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        buckets: Option<&'static [f64]>,
+        call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self {
+        Self::start_with_caller(
+            current_caller(),
+            function,
+            module,
+            labels,
+            buckets,
+            call_site,
+            track_concurrency,
+        )
+    }
+
+    fn finish(mut self, counter_labels: &[Label]) {
+        if let Some(inner) = self.inner.take() {
+            self.finish_inner(inner, counter_labels);
+        }
+    }
+}
+
+impl Drop for AutometricsTracker {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            let result = if thread::panicking() {
+                PANIC_KEY
+            } else {
+                CANCELLED_KEY
+            };
+            let counter_labels = create_label_array(
+                result,
+                inner.function(),
+                inner.module(),
+                self.caller,
+                None,
+                inner.labels(),
+            );
+            self.finish_inner(inner, &counter_labels);
+        }
+    }
 }
@@ -0,0 +1,657 @@
+use crate::{
+    buckets::default_histogram_buckets,
+    constants::*,
+    labels::{CustomLabels, Label},
+    text::{
+        escape, format_float, since_epoch, write_sample, Timestamp, HISTOGRAM_NAME_OPENMETRICS,
+        HISTOGRAM_UNIT,
+    },
+    tracker::{cache::HandleCache, CallSite, TrackMetrics},
+};
+use const_format::str_replace;
+use once_cell::sync::OnceCell;
+use smallvec::SmallVec;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+pub(crate) mod protobuf;
+
+#[cfg(feature = "exemplars")]
+use crate::exemplars::{Exemplar, ExemplarSlot, TraceIds, WithExemplar};
+#[cfg(feature = "exemplars")]
+use opentelemetry_api::Context;
+
+const COUNTER_NAME_PROMETHEUS: &str = str_replace!(COUNTER_NAME, ".", "_");
+const HISTOGRAM_NAME_PROMETHEUS: &str = str_replace!(HISTOGRAM_NAME, ".", "_");
+const GAUGE_NAME_PROMETHEUS: &str = str_replace!(GAUGE_NAME, ".", "_");
+
+/// The number of shards that counters and histograms are split into.
+///
+/// Each thread updates the shard it was assigned, so threads running on different cores
+/// rarely write to the same cache line. The shards are summed up when the metrics are encoded.
+const SHARDS: usize = 8;
+
+/// Pads the value to its own cache line
+#[repr(align(64))]
+#[derive(Default)]
+struct Shard<T>(T);
+
+fn shard_index() -> usize {
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+    }
+    SHARD.with(|shard| *shard)
+}
+
+struct Counter {
+    shards: [Shard<AtomicU64>; SHARDS],
+    /// When the time series was created, as the time since the Unix epoch
+    created: Duration,
+    #[cfg(feature = "exemplars")]
+    exemplar: ExemplarSlot,
+}
+
+impl Counter {
+    fn new() -> Self {
+        Counter {
+            shards: Default::default(),
+            created: since_epoch(),
+            #[cfg(feature = "exemplars")]
+            exemplar: ExemplarSlot::default(),
+        }
+    }
+
+    fn increment(&self) {
+        self.shards[shard_index()].0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn value(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.0.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+#[derive(Default)]
+struct Gauge(AtomicI64);
+
+struct HistogramShard {
+    /// The number of observations in each bucket (not cumulative), followed by the `+Inf` bucket
+    counts: Box<[AtomicU64]>,
+    /// The sum is kept in nanoseconds so it can be updated with a single atomic add
+    sum_nanos: AtomicU64,
+}
+
+struct Histogram {
+    buckets: &'static [f64],
+    shards: [Shard<HistogramShard>; SHARDS],
+    /// When the time series was created, as the time since the Unix epoch
+    created: Duration,
+    #[cfg(feature = "native-histograms")]
+    exponential: ExponentialBuckets,
+    /// The most recent exemplar of each bucket, including `+Inf`
+    #[cfg(feature = "exemplars")]
+    exemplars: Box<[ExemplarSlot]>,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            shards: std::array::from_fn(|_| {
+                Shard(HistogramShard {
+                    counts: (0..=buckets.len()).map(|_| AtomicU64::new(0)).collect(),
+                    sum_nanos: AtomicU64::new(0),
+                })
+            }),
+            created: since_epoch(),
+            #[cfg(feature = "native-histograms")]
+            exponential: ExponentialBuckets::new(),
+            #[cfg(feature = "exemplars")]
+            exemplars: (0..=buckets.len())
+                .map(|_| ExemplarSlot::default())
+                .collect(),
+        }
+    }
+
+    /// The index of the bucket that the duration is counted in.
+    /// Buckets are inclusive of their upper bound.
+    fn bucket(&self, seconds: f64) -> usize {
+        self.buckets.partition_point(|bound| *bound < seconds)
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self.bucket(seconds);
+        let shard = &self.shards[shard_index()].0;
+        shard.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        shard
+            .sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "native-histograms")]
+        self.exponential.observe(seconds);
+    }
+
+    /// The cumulative bucket counts (including `+Inf`) and the sum in seconds
+    fn snapshot(&self) -> (SmallVec<[u64; 16]>, f64) {
+        let mut counts: SmallVec<[u64; 16]> = SmallVec::from_elem(0, self.buckets.len() + 1);
+        let mut sum_nanos = 0;
+        for shard in &self.shards {
+            for (count, shard_count) in counts.iter_mut().zip(shard.0.counts.iter()) {
+                *count += shard_count.load(Ordering::Relaxed);
+            }
+            sum_nanos += shard.0.sum_nanos.load(Ordering::Relaxed);
+        }
+        let mut cumulative = 0;
+        for count in counts.iter_mut() {
+            cumulative += *count;
+            *count = cumulative;
+        }
+        (counts, sum_nanos as f64 / 1e9)
+    }
+
+    #[cfg(feature = "exemplars")]
+    fn set_exemplar(&self, trace: TraceIds, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        self.exemplars[self.bucket(seconds)].set(Exemplar::new(trace, seconds));
+    }
+}
+
+/// The schema of the native histograms, which splits every power of two into 2^3 = 8 buckets.
+///
+/// Each bucket is about 9% wider than the one before it, so latency quantiles and
+/// fractions are estimated with an error of at most that much for any threshold.
+#[cfg(feature = "native-histograms")]
+const EXPONENTIAL_SCHEMA: i32 = 3;
+
+/// Durations up to 2^-30 seconds (about 1 nanosecond) are counted in the zero bucket
+#[cfg(feature = "native-histograms")]
+const EXPONENTIAL_MIN_INDEX: i32 = -30 << EXPONENTIAL_SCHEMA;
+
+/// Durations over 2^16 seconds (about 18 hours) are counted in the highest bucket
+#[cfg(feature = "native-histograms")]
+const EXPONENTIAL_MAX_INDEX: i32 = 16 << EXPONENTIAL_SCHEMA;
+
+/// The buckets of a Prometheus native histogram (also known as an exponential histogram in OpenTelemetry).
+///
+/// Bucket `i` counts the durations in the range `(2^((i - 1) / 8), 2^(i / 8)]` seconds. All of the buckets
+/// in the supported range are allocated up front so that recording a duration never needs a lock.
+#[cfg(feature = "native-histograms")]
+struct ExponentialBuckets {
+    zero_count: AtomicU64,
+    /// The counts of the buckets with indexes `EXPONENTIAL_MIN_INDEX + 1..=EXPONENTIAL_MAX_INDEX`
+    counts: Box<[AtomicU64]>,
+}
+
+#[cfg(feature = "native-histograms")]
+impl ExponentialBuckets {
+    fn new() -> Self {
+        ExponentialBuckets {
+            zero_count: AtomicU64::new(0),
+            counts: (EXPONENTIAL_MIN_INDEX..EXPONENTIAL_MAX_INDEX)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    /// The upper bound of the zero bucket
+    fn zero_threshold() -> f64 {
+        2f64.powi(EXPONENTIAL_MIN_INDEX >> EXPONENTIAL_SCHEMA)
+    }
+
+    fn observe(&self, seconds: f64) {
+        if seconds <= Self::zero_threshold() {
+            self.zero_count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let index = (seconds.log2() * f64::from(1 << EXPONENTIAL_SCHEMA)).ceil() as i32;
+        let index = index.clamp(EXPONENTIAL_MIN_INDEX + 1, EXPONENTIAL_MAX_INDEX);
+        self.counts[(index - EXPONENTIAL_MIN_INDEX - 1) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The count of the zero bucket and the index and count of every bucket that is not empty
+    fn snapshot(&self) -> (u64, Vec<(i32, u64)>) {
+        let buckets = self
+            .counts
+            .iter()
+            .zip(EXPONENTIAL_MIN_INDEX + 1..)
+            .map(|(count, index)| (index, count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        (self.zero_count.load(Ordering::Relaxed), buckets)
+    }
+}
+
+type LabelSet = SmallVec<[Label; 8]>;
+
+/// All of the time series of one metric, keyed by their labels.
+///
+/// Time series are never removed, so they are leaked and handed out as `&'static` references
+/// that can be cached per call site. The number of time series is bounded because all label
+/// values are either string literals or interned with a limit per label key.
+struct Family<T: 'static> {
+    series: OnceCell<RwLock<HashMap<LabelSet, &'static T>>>,
+}
+
+impl<T: 'static> Family<T> {
+    const fn new() -> Self {
+        Family {
+            series: OnceCell::new(),
+        }
+    }
+
+    fn get_or_insert_with(&self, labels: LabelSet, create: impl FnOnce() -> T) -> &'static T {
+        let series = self.series.get_or_init(Default::default);
+        if let Some(metric) = series
+            .read()
+            .expect("autometrics native metrics lock poisoned")
+            .get(&labels)
+            .copied()
+        {
+            return metric;
+        }
+
+        series
+            .write()
+            .expect("autometrics native metrics lock poisoned")
+            .entry(labels)
+            .or_insert_with(|| Box::leak(Box::new(create())))
+    }
+
+    /// The time series sorted by their labels, so the output is stable between scrapes
+    fn snapshot(&self) -> Vec<(LabelSet, &'static T)> {
+        let mut series: Vec<_> = match self.series.get() {
+            Some(series) => series
+                .read()
+                .expect("autometrics native metrics lock poisoned")
+                .iter()
+                .map(|(labels, metric)| (labels.clone(), *metric))
+                .collect(),
+            None => Vec::new(),
+        };
+        series.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        series
+    }
+}
+
+static COUNTERS: Family<Counter> = Family::new();
+static HISTOGRAMS: Family<Histogram> = Family::new();
+static GAUGES: Family<Gauge> = Family::new();
+
+/// The labels for the histogram and gauge: the function, module, and any custom labels
+fn function_and_module_labels(
+    function: &'static str,
+    module: &'static str,
+    labels: &[Label],
+) -> LabelSet {
+    [(FUNCTION_KEY, function), (MODULE_KEY, module)]
+        .into_iter()
+        .chain(labels.iter().copied())
+        .collect()
+}
+
+/// Caches the time series of a single instrumented function, see [`CallSite`]
+pub(crate) struct CallSiteCache {
+    histograms: HandleCache<&'static Histogram>,
+    gauges: HandleCache<&'static Gauge>,
+    counters: HandleCache<&'static Counter>,
+}
+
+impl CallSiteCache {
+    pub(crate) const fn new() -> Self {
+        CallSiteCache {
+            histograms: HandleCache::new(),
+            gauges: HandleCache::new(),
+            counters: HandleCache::new(),
+        }
+    }
+}
+
+/// Records function calls in the metrics stored by autometrics itself
+pub struct NativeTracker {
+    module: &'static str,
+    function: &'static str,
+    labels: CustomLabels,
+    histogram: &'static Histogram,
+    gauge: Option<&'static Gauge>,
+    cache: Option<&'static CallSiteCache>,
+    #[cfg(feature = "exemplars")]
+    trace: Option<TraceIds>,
+    start: Instant,
+}
+
+impl TrackMetrics for NativeTracker {
+    fn function(&self) -> &'static str {
+        self.function
+    }
+
+    fn module(&self) -> &'static str {
+        self.module
+    }
+
+    fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    fn start(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        buckets: Option<&'static [f64]>,
+        call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self {
+        let cache = call_site.map(|call_site| &call_site.native);
+
+        // The function and module are the same for every call from the same call site,
+        // so only the custom labels need to be part of the cache key
+        let create_histogram = || {
+            HISTOGRAMS
+                .get_or_insert_with(function_and_module_labels(function, module, labels), || {
+                    Histogram::new(buckets.unwrap_or_else(default_histogram_buckets))
+                })
+        };
+        let histogram = match cache {
+            Some(cache) => cache
+                .histograms
+                .get_or_insert_with(labels, create_histogram),
+            None => create_histogram(),
+        };
+
+        let gauge = if track_concurrency {
+            let create_gauge = || {
+                GAUGES.get_or_insert_with(
+                    function_and_module_labels(function, module, labels),
+                    Gauge::default,
+                )
+            };
+            let gauge = match cache {
+                Some(cache) => cache.gauges.get_or_insert_with(labels, create_gauge),
+                None => create_gauge(),
+            };
+            gauge.0.fetch_add(1, Ordering::Relaxed);
+            Some(gauge)
+        } else {
+            None
+        };
+
+        Self {
+            function,
+            module,
+            labels: CustomLabels::from_slice(labels),
+            histogram,
+            gauge,
+            cache,
+            #[cfg(feature = "exemplars")]
+            trace: TraceIds::from_context(&Context::current()),
+            start: Instant::now(),
+        }
+    }
+
+    fn finish(self, counter_labels: &[Label]) {
+        let duration = self.start.elapsed();
+
+        let create_counter =
+            || COUNTERS.get_or_insert_with(LabelSet::from_slice(counter_labels), Counter::new);
+        let counter = match self.cache {
+            Some(cache) => cache
+                .counters
+                .get_or_insert_with(counter_labels, create_counter),
+            None => create_counter(),
+        };
+        counter.increment();
+
+        self.histogram.observe(duration);
+
+        #[cfg(feature = "exemplars")]
+        if let Some(trace) = self.trace {
+            counter.exemplar.set(Exemplar::new(trace, 1.0));
+            self.histogram.set_exemplar(trace, duration);
+        }
+
+        if let Some(gauge) = self.gauge {
+            gauge.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The text formats that the native metrics can be encoded in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TextFormat {
+    /// The Prometheus text exposition format, version 0.0.4
+    Prometheus,
+    /// The [OpenMetrics](https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md) text format
+    ///
+    /// This includes the exemplars of the call counter and histogram buckets if the `exemplars` feature is enabled.
+    OpenMetrics,
+}
+
+/// Encode the metrics recorded by the `native` metrics library in one of the text formats.
+///
+/// This does not depend on any other crates, so it can be used to serve the metrics
+/// without enabling the `prometheus-exporter` feature. If that feature is enabled,
+/// [`encode_global_metrics`](crate::encode_global_metrics) includes these metrics as well.
+pub fn encode_native_metrics(format: TextFormat) -> String {
+    let mut output = String::new();
+    encode(&mut output, format, &[]);
+    if format == TextFormat::OpenMetrics {
+        output.push_str("# EOF\n");
+    }
+    output
+}
+
+/// Encode the metrics recorded by the `native` metrics library in the Prometheus protobuf format.
+///
+/// The output is a sequence of length-delimited `io.prometheus.client.MetricFamily` messages, which should
+/// be served with the content type `application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited`.
+/// This is the only format that supports native histograms, which are included if the `native-histograms`
+/// feature is enabled.
+pub fn encode_native_metrics_protobuf() -> Vec<u8> {
+    let mut output = Vec::new();
+    protobuf::encode(&mut output, &[]);
+    output
+}
+
+/// Whether the metric family with the given name is one of the autometrics metrics,
+/// which are encoded from the native metrics when they are exported with other metrics
+#[cfg(feature = "prometheus-exporter")]
+pub(crate) fn is_autometrics_metric(name: &str) -> bool {
+    [
+        COUNTER_NAME_PROMETHEUS,
+        HISTOGRAM_NAME_PROMETHEUS,
+        GAUGE_NAME_PROMETHEUS,
+    ]
+    .contains(&name)
+}
+
+/// Write the native metrics to the output, adding the constant labels to every time series.
+///
+/// For the OpenMetrics format, this does not write the `# EOF` line so that
+/// the output can be combined with other metrics.
+pub(crate) fn encode(output: &mut String, format: TextFormat, const_labels: &[(String, String)]) {
+    // Writing to a String cannot fail
+    let _ = write_metrics(output, format, const_labels);
+}
+
+fn write_metrics(
+    output: &mut String,
+    format: TextFormat,
+    const_labels: &[(String, String)],
+) -> fmt::Result {
+    let counters = COUNTERS.snapshot();
+    if !counters.is_empty() {
+        write_header(
+            output,
+            COUNTER_NAME_PROMETHEUS,
+            "counter",
+            COUNTER_DESCRIPTION,
+        )?;
+        // OpenMetrics requires the samples of a counter to end in `_total`.
+        // The queries generated by autometrics match the counter with and without the suffix
+        let sample_name = match format {
+            TextFormat::Prometheus => Cow::Borrowed(COUNTER_NAME_PROMETHEUS),
+            TextFormat::OpenMetrics => Cow::Owned([COUNTER_NAME_PROMETHEUS, "_total"].concat()),
+        };
+        let created_name = [COUNTER_NAME_PROMETHEUS, "_created"].concat();
+        for (labels, counter) in counters {
+            write_sample(
+                output,
+                &sample_name,
+                series_labels(&labels, const_labels),
+                counter_value(counter, format),
+            )?;
+            if format == TextFormat::OpenMetrics {
+                write_sample(
+                    output,
+                    &created_name,
+                    series_labels(&labels, const_labels),
+                    Timestamp(counter.created),
+                )?;
+            }
+        }
+    }
+
+    let histograms = HISTOGRAMS.snapshot();
+    if !histograms.is_empty() {
+        // OpenMetrics requires the name to end with the unit.
+        // The queries generated by autometrics match the histogram with and without it
+        let name = match format {
+            TextFormat::Prometheus => HISTOGRAM_NAME_PROMETHEUS,
+            TextFormat::OpenMetrics => HISTOGRAM_NAME_OPENMETRICS,
+        };
+        write_header(output, name, "histogram", HISTOGRAM_DESCRIPTION)?;
+        if format == TextFormat::OpenMetrics {
+            writeln!(output, "# UNIT {name} {HISTOGRAM_UNIT}")?;
+        }
+        let bucket_name = [name, "_bucket"].concat();
+        let sum_name = [name, "_sum"].concat();
+        let count_name = [name, "_count"].concat();
+        let created_name = [name, "_created"].concat();
+        for (labels, histogram) in histograms {
+            let (counts, sum) = histogram.snapshot();
+            let bounds = histogram
+                .buckets
+                .iter()
+                .map(|bound| format_float(*bound))
+                .chain([Cow::Borrowed("+Inf")]);
+            for (bucket, (bound, count)) in bounds.zip(counts.iter()).enumerate() {
+                write_sample(
+                    output,
+                    &bucket_name,
+                    series_labels(&labels, const_labels).chain([("le", &*bound)]),
+                    bucket_value(histogram, bucket, *count, format),
+                )?;
+            }
+            let total = counts[counts.len() - 1];
+            write_sample(
+                output,
+                &sum_name,
+                series_labels(&labels, const_labels),
+                format_float(sum),
+            )?;
+            write_sample(
+                output,
+                &count_name,
+                series_labels(&labels, const_labels),
+                total,
+            )?;
+            if format == TextFormat::OpenMetrics {
+                write_sample(
+                    output,
+                    &created_name,
+                    series_labels(&labels, const_labels),
+                    Timestamp(histogram.created),
+                )?;
+            }
+        }
+    }
+
+    let gauges = GAUGES.snapshot();
+    if !gauges.is_empty() {
+        write_header(output, GAUGE_NAME_PROMETHEUS, "gauge", GAUGE_DESCRIPTION)?;
+        for (labels, gauge) in gauges {
+            write_sample(
+                output,
+                GAUGE_NAME_PROMETHEUS,
+                series_labels(&labels, const_labels),
+                gauge.0.load(Ordering::Relaxed),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The value of a counter, followed by the counter's exemplar in the OpenMetrics format
+#[cfg(feature = "exemplars")]
+fn counter_value(counter: &Counter, format: TextFormat) -> impl fmt::Display {
+    let exemplar = match format {
+        TextFormat::OpenMetrics => counter.exemplar.get(),
+        TextFormat::Prometheus => None,
+    };
+    WithExemplar(counter.value(), exemplar)
+}
+
+#[cfg(not(feature = "exemplars"))]
+fn counter_value(counter: &Counter, _format: TextFormat) -> impl fmt::Display {
+    counter.value()
+}
+
+/// The count of a histogram bucket, followed by the bucket's exemplar in the OpenMetrics format
+#[cfg(feature = "exemplars")]
+fn bucket_value(
+    histogram: &Histogram,
+    bucket: usize,
+    count: u64,
+    format: TextFormat,
+) -> impl fmt::Display {
+    let exemplar = match format {
+        TextFormat::OpenMetrics => histogram.exemplars[bucket].get(),
+        TextFormat::Prometheus => None,
+    };
+    WithExemplar(count, exemplar)
+}
+
+#[cfg(not(feature = "exemplars"))]
+fn bucket_value(
+    _histogram: &Histogram,
+    _bucket: usize,
+    count: u64,
+    _format: TextFormat,
+) -> impl fmt::Display {
+    count
+}
+
+/// The labels of a time series followed by the constant labels.
+///
+/// Empty label values are the same as a missing label, so they are left out.
+fn series_labels<'a>(
+    labels: &'a [Label],
+    const_labels: &'a [(String, String)],
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    labels
+        .iter()
+        .map(|(key, value)| (*key, *value))
+        .chain(
+            const_labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )
+        .filter(|(_, value)| !value.is_empty())
+}
+
+fn write_header(output: &mut String, name: &str, metric_type: &str, help: &str) -> fmt::Result {
+    writeln!(output, "# HELP {name} {}", escape(help, false))?;
+    writeln!(output, "# TYPE {name} {metric_type}")
+}
@@ -0,0 +1,148 @@
+//! Encodes the native metrics in the Prometheus protobuf exposition format
+
+use super::{series_labels, Histogram, COUNTERS, GAUGES, HISTOGRAMS};
+use super::{COUNTER_NAME_PROMETHEUS, GAUGE_NAME_PROMETHEUS, HISTOGRAM_NAME_PROMETHEUS};
+use crate::constants::*;
+#[cfg(feature = "exemplars")]
+use crate::exemplars::Exemplar;
+use crate::protobuf::*;
+use std::sync::atomic::Ordering;
+
+/// Write the native metrics to the output, adding the constant labels to every time series
+pub(crate) fn encode(output: &mut Vec<u8>, const_labels: &[(String, String)]) {
+    let counters = COUNTERS.snapshot();
+    if !counters.is_empty() {
+        let mut family = family(COUNTER_NAME_PROMETHEUS, COUNTER_DESCRIPTION, TYPE_COUNTER);
+        for (labels, counter) in counters {
+            let mut value = Vec::new();
+            write_double(&mut value, 1, counter.value() as f64);
+            #[cfg(feature = "exemplars")]
+            if let Some(exemplar) = counter.exemplar.get() {
+                write_message(&mut value, 2, &encode_exemplar(&exemplar));
+            }
+            write_timestamp(&mut value, 3, counter.created);
+            let mut metric = metric(series_labels(&labels, const_labels));
+            write_message(&mut metric, 3, &value);
+            write_message(&mut family, 4, &metric);
+        }
+        write_delimited(output, &family);
+    }
+
+    let histograms = HISTOGRAMS.snapshot();
+    if !histograms.is_empty() {
+        let mut family = family(
+            HISTOGRAM_NAME_PROMETHEUS,
+            HISTOGRAM_DESCRIPTION,
+            TYPE_HISTOGRAM,
+        );
+        for (labels, histogram) in histograms {
+            let mut metric = metric(series_labels(&labels, const_labels));
+            write_message(&mut metric, 7, &encode_histogram(histogram));
+            write_message(&mut family, 4, &metric);
+        }
+        write_delimited(output, &family);
+    }
+
+    let gauges = GAUGES.snapshot();
+    if !gauges.is_empty() {
+        let mut family = family(GAUGE_NAME_PROMETHEUS, GAUGE_DESCRIPTION, TYPE_GAUGE);
+        for (labels, gauge) in gauges {
+            let mut value = Vec::new();
+            write_double(&mut value, 1, gauge.0.load(Ordering::Relaxed) as f64);
+            let mut metric = metric(series_labels(&labels, const_labels));
+            write_message(&mut metric, 2, &value);
+            write_message(&mut family, 4, &metric);
+        }
+        write_delimited(output, &family);
+    }
+}
+
+fn encode_histogram(histogram: &Histogram) -> Vec<u8> {
+    let (counts, sum) = histogram.snapshot();
+    let total = counts[counts.len() - 1];
+
+    let mut message = Vec::new();
+    write_varint_field(&mut message, 1, total);
+    write_double(&mut message, 2, sum);
+    // The `+Inf` bucket is implied by the count
+    for (index, (bound, count)) in histogram.buckets.iter().zip(counts.iter()).enumerate() {
+        let mut bucket = Vec::new();
+        write_varint_field(&mut bucket, 1, *count);
+        write_double(&mut bucket, 2, *bound);
+        write_bucket_exemplar(&mut bucket, histogram, index);
+        write_message(&mut message, 3, &bucket);
+    }
+
+    write_timestamp(&mut message, 15, histogram.created);
+
+    #[cfg(feature = "native-histograms")]
+    encode_exponential_buckets(&mut message, histogram);
+
+    message
+}
+
+#[cfg(feature = "exemplars")]
+fn write_bucket_exemplar(bucket: &mut Vec<u8>, histogram: &Histogram, index: usize) {
+    if let Some(exemplar) = histogram.exemplars[index].get() {
+        write_message(bucket, 3, &encode_exemplar(&exemplar));
+    }
+}
+
+#[cfg(not(feature = "exemplars"))]
+fn write_bucket_exemplar(_bucket: &mut Vec<u8>, _histogram: &Histogram, _index: usize) {}
+
+#[cfg(feature = "exemplars")]
+fn encode_exemplar(exemplar: &Exemplar) -> Vec<u8> {
+    let mut message = Vec::new();
+    for (name, value) in &exemplar.labels() {
+        write_label(&mut message, 1, name, value);
+    }
+    write_double(&mut message, 2, exemplar.value);
+    write_timestamp(&mut message, 3, exemplar.timestamp);
+    message
+}
+
+/// Add the fields of a native histogram to the `Histogram` message
+#[cfg(feature = "native-histograms")]
+fn encode_exponential_buckets(message: &mut Vec<u8>, histogram: &Histogram) {
+    use super::{ExponentialBuckets, EXPONENTIAL_SCHEMA};
+
+    let (zero_count, buckets) = histogram.exponential.snapshot();
+    write_varint_field(message, 5, zigzag(EXPONENTIAL_SCHEMA.into()));
+    write_double(message, 6, ExponentialBuckets::zero_threshold());
+    write_varint_field(message, 7, zero_count);
+
+    // The buckets are encoded as spans of consecutive buckets, where each span's offset is relative
+    // to the end of the previous span, and the count of each bucket is relative to the previous bucket
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut next_index = 0;
+    for (index, _) in &buckets {
+        match spans.last_mut() {
+            Some((_, length)) if *index == next_index => *length += 1,
+            _ => spans.push((index - next_index, 1)),
+        }
+        next_index = index + 1;
+    }
+    // A histogram without any spans would be read as a classic histogram
+    if spans.is_empty() {
+        spans.push((0, 0));
+    }
+
+    for (offset, length) in spans {
+        let mut span = Vec::new();
+        write_varint_field(&mut span, 1, zigzag(offset.into()));
+        write_varint_field(&mut span, 2, length.into());
+        write_message(message, 12, &span);
+    }
+
+    let mut previous = 0;
+    for (_, count) in buckets {
+        write_varint_field(message, 13, zigzag(count as i64 - previous as i64));
+        previous = count;
+    }
+}
+
+#[cfg(feature = "native-histograms")]
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
@@ -1,13 +1,119 @@
-use crate::{constants::*, labels::Label, tracker::TrackMetrics};
-use opentelemetry_api::{global, metrics::UpDownCounter, Context, KeyValue};
+#[cfg(all(
+    feature = "exemplars",
+    feature = "prometheus-exporter",
+    not(feature = "native")
+))]
+use crate::exemplars::{store, TraceIds};
+use crate::{
+    constants::*,
+    labels::{CustomLabels, Label},
+    tracker::{CallSite, TrackMetrics},
+};
+use opentelemetry_api::{
+    global,
+    metrics::{Counter, Histogram, MeterProvider, UpDownCounter},
+    Context, KeyValue,
+};
+use smallvec::SmallVec;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
+/// Enough space for the counter labels of most functions without allocating
+type KeyValues = SmallVec<[KeyValue; 8]>;
+
+/// The instruments are created the first time a function is called and reused for every call
+/// after that, together with the generation of the meter provider they were created with.
+static INSTRUMENTS: RwLock<Option<(usize, Arc<Instruments>)>> = RwLock::new(None);
+
+/// Incremented every time autometrics installs a global meter provider.
+///
+/// Instruments are bound to the meter provider that was installed when they were created, and
+/// the OpenTelemetry API does not expose which provider is currently installed, so this is how
+/// the cached instruments know that they need to be created again.
+static METER_PROVIDER_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+struct Instruments {
+    counter: Counter<f64>,
+    histogram: Histogram<f64>,
+    gauge: UpDownCounter<i64>,
+}
+
+impl Instruments {
+    fn new() -> Self {
+        let meter = global::meter("");
+        Instruments {
+            counter: meter
+                .f64_counter(COUNTER_NAME)
+                .with_description(COUNTER_DESCRIPTION)
+                .init(),
+            histogram: meter
+                .f64_histogram(HISTOGRAM_NAME)
+                .with_description(HISTOGRAM_DESCRIPTION)
+                .init(),
+            gauge: meter
+                .i64_up_down_counter(GAUGE_NAME)
+                .with_description(GAUGE_DESCRIPTION)
+                .init(),
+        }
+    }
+
+    /// Get the instruments for the current meter provider, creating them if necessary
+    fn get() -> Arc<Instruments> {
+        let generation = METER_PROVIDER_GENERATION.load(Ordering::Acquire);
+        if let Some((cached_generation, instruments)) = &*INSTRUMENTS
+            .read()
+            .expect("autometrics instruments lock poisoned")
+        {
+            if *cached_generation == generation {
+                return instruments.clone();
+            }
+        }
+
+        let mut cached = INSTRUMENTS
+            .write()
+            .expect("autometrics instruments lock poisoned");
+        match &*cached {
+            // Another thread may have created them in the meantime
+            Some((cached_generation, instruments)) if *cached_generation == generation => {
+                instruments.clone()
+            }
+            _ => {
+                let instruments = Arc::new(Instruments::new());
+                *cached = Some((generation, instruments.clone()));
+                instruments
+            }
+        }
+    }
+}
+
+/// Make the instruments use the meter provider that was just installed
+pub(crate) fn meter_provider_changed() {
+    METER_PROVIDER_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Set the global OpenTelemetry meter provider that the autometrics metrics are recorded with.
+///
+/// Use this instead of [`opentelemetry_api::global::set_meter_provider`] if the provider
+/// is installed after the first instrumented function was called. Autometrics creates the
+/// OpenTelemetry instruments once and reuses them, and this makes it create them again
+/// with the new provider. The exporters included in autometrics do this automatically.
+pub fn set_meter_provider<P>(provider: P)
+where
+    P: MeterProvider + Send + Sync + 'static,
+{
+    global::set_meter_provider(provider);
+    meter_provider_changed();
+}
+
 /// Tracks the number of function calls, concurrent calls, and latency
 pub struct OpenTelemetryTracker {
     module: &'static str,
     function: &'static str,
-    concurrency_tracker: Option<UpDownCounter<i64>>,
-    function_and_module_labels: [KeyValue; 2],
+    labels: CustomLabels,
+    instruments: Arc<Instruments>,
+    track_concurrency: bool,
+    function_and_module_labels: KeyValues,
     start: Instant,
     context: Context,
 }
@@ -20,59 +126,90 @@ impl TrackMetrics for OpenTelemetryTracker {
         self.module
     }
 
-    fn start(function: &'static str, module: &'static str, track_concurrency: bool) -> Self {
-        let function_and_module_labels = [
-            KeyValue::new(FUNCTION_KEY, function),
-            KeyValue::new(MODULE_KEY, module),
-        ];
+    fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    fn start(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        _buckets: Option<&'static [f64]>,
+        _call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self {
+        // The histogram buckets cannot be set per time series with this library,
+        // so they are configured by the exporter instead.
+        // The instruments are shared by all functions, so they are cached globally rather than per call site.
+        // The histogram and gauge are labeled with the function, module, and any custom labels
+        let function_and_module_labels: KeyValues =
+            [(FUNCTION_KEY, function), (MODULE_KEY, module)]
+                .iter()
+                .chain(labels)
+                .map(|(k, v)| KeyValue::new(*k, *v))
+                .collect();
 
         let context = Context::current();
-        let concurrency_tracker = if track_concurrency {
+        let instruments = Instruments::get();
+        if track_concurrency {
             // Increase the number of concurrent requests
-            let concurrency_tracker = global::meter("")
-                .i64_up_down_counter(GAUGE_NAME)
-                .with_description(GAUGE_DESCRIPTION)
-                .init();
-            concurrency_tracker.add(&context, 1, &function_and_module_labels);
-            Some(concurrency_tracker)
-        } else {
-            None
-        };
+            instruments
+                .gauge
+                .add(&context, 1, &function_and_module_labels);
+        }
 
         Self {
             function,
             module,
+            labels: CustomLabels::from_slice(labels),
+            instruments,
+            track_concurrency,
             function_and_module_labels,
-            concurrency_tracker,
             start: Instant::now(),
             context,
         }
     }
 
-    fn finish<'a>(self, counter_labels: &[Label]) {
+    fn finish(self, counter_labels: &[Label]) {
         let duration = self.start.elapsed().as_secs_f64();
 
-        // Track the function calls
-        let counter_labels: Vec<KeyValue> = counter_labels
-            .into_iter()
+        // Keep the trace of the call for the exporter, because OpenTelemetry does not record exemplars
+        #[cfg(all(
+            feature = "exemplars",
+            feature = "prometheus-exporter",
+            not(feature = "native")
+        ))]
+        if let Some(trace) = TraceIds::from_context(&self.context) {
+            let histogram_labels: SmallVec<[Label; 8]> =
+                [(FUNCTION_KEY, self.function), (MODULE_KEY, self.module)]
+                    .into_iter()
+                    .chain(self.labels.iter().copied())
+                    .collect();
+            store::record(trace, counter_labels, &histogram_labels, duration);
+        }
+
+        let counter_labels: KeyValues = counter_labels
+            .iter()
             .map(|(k, v)| KeyValue::new(*k, *v))
             .collect();
-        let counter = global::meter("")
-            .f64_counter(COUNTER_NAME)
-            .with_description(COUNTER_DESCRIPTION)
-            .init();
-        counter.add(&self.context, 1.0, &counter_labels);
+
+        // Track the function calls
+        self.instruments
+            .counter
+            .add(&self.context, 1.0, &counter_labels);
 
         // Track the latency
-        let histogram = global::meter("")
-            .f64_histogram(HISTOGRAM_NAME)
-            .with_description(HISTOGRAM_DESCRIPTION)
-            .init();
-        histogram.record(&self.context, duration, &self.function_and_module_labels);
+        self.instruments.histogram.record(
+            &self.context,
+            duration,
+            &self.function_and_module_labels,
+        );
 
         // Decrease the number of concurrent requests
-        if let Some(concurrency_tracker) = self.concurrency_tracker {
-            concurrency_tracker.add(&self.context, -1, &self.function_and_module_labels);
+        if self.track_concurrency {
+            self.instruments
+                .gauge
+                .add(&self.context, -1, &self.function_and_module_labels);
         }
     }
 }
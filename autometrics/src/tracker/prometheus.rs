@@ -1,55 +1,260 @@
-use crate::{constants::*, labels::Label, tracker::TrackMetrics};
+use crate::{
+    buckets::default_histogram_buckets,
+    constants::*,
+    labels::{CustomLabels, Label},
+    tracker::{cache::HandleCache, CallSite, TrackMetrics},
+};
 use const_format::{formatcp, str_replace};
 use once_cell::sync::Lazy;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
 use prometheus::{
-    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec,
-    IntCounterVec, IntGaugeVec,
+    default_registry, histogram_opts, opts, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Registry,
+};
+use smallvec::SmallVec;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Instant,
 };
-use std::{collections::HashMap, time::Instant};
 
 const COUNTER_NAME_PROMETHEUS: &str = str_replace!(COUNTER_NAME, ".", "_");
 const HISTOGRAM_NAME_PROMETHEUS: &str = str_replace!(HISTOGRAM_NAME, ".", "_");
 const GAUGE_NAME_PROMETHEUS: &str = str_replace!(GAUGE_NAME, ".", "_");
 
-static COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
-    register_int_counter_vec!(
-        COUNTER_NAME_PROMETHEUS,
-        COUNTER_DESCRIPTION,
-        &[
+/// The prometheus crate requires the label names and histogram buckets of a metric to be known
+/// when the metric is created. Since functions can have custom labels and buckets, we keep a separate
+/// set of metrics for every combination of those and expose all of them through a single collector.
+static METRICS: Lazy<RwLock<HashMap<MetricsKey, Arc<Metrics>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+#[derive(PartialEq, Eq, Hash)]
+struct MetricsKey {
+    custom_label_keys: Vec<&'static str>,
+    /// The bit patterns of the histogram buckets, because f64 does not implement Eq or Hash
+    buckets: Vec<u64>,
+}
+
+static COLLECTOR: Lazy<()> = Lazy::new(|| {
+    register_collector(default_registry()).expect(formatcp!(
+        "Failed to register {COUNTER_NAME_PROMETHEUS}, {HISTOGRAM_NAME_PROMETHEUS}, and {GAUGE_NAME_PROMETHEUS} metrics"
+    ));
+});
+
+/// Register the autometrics metrics in the given registry.
+///
+/// They are always registered in the default registry when the first function call is tracked.
+pub(crate) fn register_collector(registry: &Registry) -> prometheus::Result<()> {
+    registry.register(Box::new(AutometricsCollector::new()))
+}
+
+struct Metrics {
+    counter: IntCounterVec,
+    histogram: HistogramVec,
+    gauge: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new(custom_label_keys: &[&'static str], buckets: &[f64]) -> Self {
+        let counter_label_keys: Vec<&str> = [
             FUNCTION_KEY,
             MODULE_KEY,
             CALLER_KEY,
+            CALLER_SERVICE_KEY,
             RESULT_KEY,
             OK_KEY,
             ERROR_KEY,
         ]
-    )
-    .expect(formatcp!(
-        "Failed to register {COUNTER_NAME_PROMETHEUS} counter"
-    ))
-});
-static HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
-    register_histogram_vec!(
-        HISTOGRAM_NAME_PROMETHEUS,
-        HISTOGRAM_DESCRIPTION,
-        &[FUNCTION_KEY, MODULE_KEY]
-    )
-    .expect("Failed to register function_calls_duration histogram")
-});
-static GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
-    register_int_gauge_vec!(
-        GAUGE_NAME_PROMETHEUS,
-        GAUGE_DESCRIPTION,
-        &[FUNCTION_KEY, MODULE_KEY]
-    )
-    .expect("Failed to register function_calls_concurrent gauge")
-});
+        .iter()
+        .chain(custom_label_keys)
+        .copied()
+        .collect();
+        let label_keys: Vec<&str> = [FUNCTION_KEY, MODULE_KEY]
+            .iter()
+            .chain(custom_label_keys)
+            .copied()
+            .collect();
+
+        let histogram_opts = histogram_opts!(
+            HISTOGRAM_NAME_PROMETHEUS,
+            HISTOGRAM_DESCRIPTION,
+            buckets.to_vec()
+        );
+
+        Metrics {
+            counter: IntCounterVec::new(
+                opts!(COUNTER_NAME_PROMETHEUS, COUNTER_DESCRIPTION),
+                &counter_label_keys,
+            )
+            .expect(formatcp!(
+                "Failed to create {COUNTER_NAME_PROMETHEUS} counter"
+            )),
+            histogram: HistogramVec::new(histogram_opts, &label_keys)
+                .expect("Failed to create function_calls_duration histogram"),
+            gauge: IntGaugeVec::new(opts!(GAUGE_NAME_PROMETHEUS, GAUGE_DESCRIPTION), &label_keys)
+                .expect("Failed to create function_calls_concurrent gauge"),
+        }
+    }
+
+    /// Get (or create) the metrics for the given set of custom label keys and histogram buckets
+    fn get_or_create(labels: &[Label], buckets: Option<&'static [f64]>) -> Arc<Metrics> {
+        Lazy::force(&COLLECTOR);
+
+        let buckets = buckets.unwrap_or_else(default_histogram_buckets);
+        let key = MetricsKey {
+            custom_label_keys: labels.iter().map(|(key, _)| *key).collect(),
+            buckets: buckets.iter().map(|bucket| bucket.to_bits()).collect(),
+        };
+        if let Some(metrics) = METRICS
+            .read()
+            .expect("autometrics metrics lock poisoned")
+            .get(&key)
+        {
+            return metrics.clone();
+        }
+
+        METRICS
+            .write()
+            .expect("autometrics metrics lock poisoned")
+            .entry(key)
+            .or_insert_with_key(|key| Arc::new(Metrics::new(&key.custom_label_keys, buckets)))
+            .clone()
+    }
+}
+
+/// Collects the autometrics metrics for all combinations of custom label keys
+/// and histogram buckets and merges them into a single metric family per metric
+struct AutometricsCollector {
+    descs: Vec<Desc>,
+}
+
+impl AutometricsCollector {
+    fn new() -> Self {
+        // The registry requires at least one descriptor per metric name,
+        // so we use the ones from the metrics without custom labels
+        let metrics = Metrics::new(&[], default_histogram_buckets());
+        let descs = metrics
+            .counter
+            .desc()
+            .into_iter()
+            .chain(metrics.histogram.desc())
+            .chain(metrics.gauge.desc())
+            .cloned()
+            .collect();
+        AutometricsCollector { descs }
+    }
+}
+
+impl Collector for AutometricsCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families: Vec<MetricFamily> = Vec::new();
+        let metrics = METRICS.read().expect("autometrics metrics lock poisoned");
+        for metrics in metrics.values() {
+            let collected = metrics
+                .counter
+                .collect()
+                .into_iter()
+                .chain(metrics.histogram.collect())
+                .chain(metrics.gauge.collect());
+
+            for mut family in collected {
+                match families
+                    .iter_mut()
+                    .find(|existing| existing.get_name() == family.get_name())
+                {
+                    Some(existing) => {
+                        for metric in family.take_metric().into_iter() {
+                            existing.mut_metric().push(metric);
+                        }
+                    }
+                    None => families.push(family),
+                }
+            }
+        }
+        families
+    }
+}
+
+/// Caches the metrics of a single instrumented function, see [`CallSite`]
+pub(crate) struct CallSiteCache {
+    /// The metrics for the custom labels, along with the histogram for those label values
+    histograms: HandleCache<(Arc<Metrics>, Histogram)>,
+    gauges: HandleCache<IntGauge>,
+    counters: HandleCache<IntCounter>,
+}
+
+impl CallSiteCache {
+    pub(crate) const fn new() -> Self {
+        CallSiteCache {
+            histograms: HandleCache::new(),
+            gauges: HandleCache::new(),
+            counters: HandleCache::new(),
+        }
+    }
+}
 
 pub struct PrometheusTracker {
     module: &'static str,
     function: &'static str,
+    labels: CustomLabels,
+    metrics: Arc<Metrics>,
+    histogram: Histogram,
+    gauge: Option<IntGauge>,
+    cache: Option<&'static CallSiteCache>,
     start: Instant,
-    track_concurrency: bool,
+}
+
+impl PrometheusTracker {
+    /// The label values for the histogram and gauge: the function, module, and any custom labels
+    fn label_values(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+    ) -> SmallVec<[&'static str; 8]> {
+        [function, module]
+            .into_iter()
+            .chain(labels.iter().map(|(_, value)| *value))
+            .collect()
+    }
+
+    fn counter(&self, counter_labels: &[Label]) -> IntCounter {
+        let create = || {
+            let get_label = |key: &str| {
+                counter_labels
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| *v)
+                    .unwrap_or("")
+            };
+            // Put the label values in the same order as the keys in the counter definition
+            let counter_label_values: SmallVec<[&str; 8]> = [
+                FUNCTION_KEY,
+                MODULE_KEY,
+                CALLER_KEY,
+                CALLER_SERVICE_KEY,
+                RESULT_KEY,
+                OK_KEY,
+                ERROR_KEY,
+            ]
+            .into_iter()
+            .chain(self.labels.iter().map(|(key, _)| *key))
+            .map(get_label)
+            .collect();
+            self.metrics
+                .counter
+                .with_label_values(&counter_label_values)
+        };
+
+        match self.cache {
+            Some(cache) => cache.counters.get_or_insert_with(counter_labels, create),
+            None => create(),
+        }
+    }
 }
 
 impl TrackMetrics for PrometheusTracker {
@@ -61,43 +266,71 @@ impl TrackMetrics for PrometheusTracker {
         self.module
     }
 
-    fn start(function: &'static str, module: &'static str, track_concurrency: bool) -> Self {
-        if track_concurrency {
-            GAUGE.with_label_values(&[function, module]).inc();
-        }
+    fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    fn start(
+        function: &'static str,
+        module: &'static str,
+        labels: &[Label],
+        buckets: Option<&'static [f64]>,
+        call_site: Option<&'static CallSite>,
+        track_concurrency: bool,
+    ) -> Self {
+        let cache = call_site.map(|call_site| &call_site.prometheus);
+
+        // The function and module are the same for every call from the same call site,
+        // so only the custom labels need to be part of the cache key
+        let create_histogram = || {
+            let metrics = Metrics::get_or_create(labels, buckets);
+            let histogram = metrics
+                .histogram
+                .with_label_values(&Self::label_values(function, module, labels));
+            (metrics, histogram)
+        };
+        let (metrics, histogram) = match cache {
+            Some(cache) => cache
+                .histograms
+                .get_or_insert_with(labels, create_histogram),
+            None => create_histogram(),
+        };
+
+        let gauge = if track_concurrency {
+            let create_gauge = || {
+                metrics
+                    .gauge
+                    .with_label_values(&Self::label_values(function, module, labels))
+            };
+            let gauge = match cache {
+                Some(cache) => cache.gauges.get_or_insert_with(labels, create_gauge),
+                None => create_gauge(),
+            };
+            gauge.inc();
+            Some(gauge)
+        } else {
+            None
+        };
 
         Self {
             function,
             module,
+            labels: CustomLabels::from_slice(labels),
+            metrics,
+            histogram,
+            gauge,
+            cache,
             start: Instant::now(),
-            track_concurrency,
         }
     }
 
     fn finish(self, counter_labels: &[Label]) {
         let duration = self.start.elapsed().as_secs_f64();
 
-        let labels: HashMap<&str, &str> = counter_labels.iter().map(|(k, v)| (*k, *v)).collect();
-        COUNTER
-            .with_label_values(
-                // Put the label values in the same order as the keys in the counter definition
-                &[
-                    labels.get(FUNCTION_KEY).unwrap_or(&""),
-                    labels.get(MODULE_KEY).unwrap_or(&""),
-                    labels.get(CALLER_KEY).unwrap_or(&""),
-                    labels.get(RESULT_KEY).unwrap_or(&""),
-                    labels.get(OK_KEY).unwrap_or(&""),
-                    labels.get(ERROR_KEY).unwrap_or(&""),
-                ],
-            )
-            .inc();
-
-        HISTOGRAM
-            .with_label_values(&[self.function, self.module])
-            .observe(duration);
-
-        if self.track_concurrency {
-            GAUGE.with_label_values(&[self.function, self.module]).dec();
+        self.counter(counter_labels).inc();
+        self.histogram.observe(duration);
+        if let Some(gauge) = self.gauge {
+            gauge.dec();
         }
     }
 }
@@ -0,0 +1,143 @@
+#![cfg(feature = "prometheus-exporter")]
+
+use autometrics::autometrics;
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+#[test]
+fn caller_is_propagated_to_threads() {
+    let _ = autometrics::global_metrics_exporter();
+
+    spawns_thread();
+
+    assert!(has_caller("in_thread", "spawns_thread"));
+}
+
+#[test]
+fn caller_is_propagated_to_futures() {
+    let _ = autometrics::global_metrics_exporter();
+
+    // Poll the future outside of the function that created it, like an executor would
+    let mut future = Box::pin(creates_future());
+    let waker = Waker::from(Arc::new(NoopWaker));
+    assert!(matches!(
+        future.as_mut().poll(&mut Context::from_waker(&waker)),
+        Poll::Ready(Ok(()))
+    ));
+
+    assert!(has_caller("in_future", "creates_future"));
+}
+
+#[test]
+fn caller_is_propagated_across_services() {
+    let _ = autometrics::global_metrics_exporter();
+    autometrics::set_service_name("upstream");
+
+    let headers = makes_request();
+    assert!(headers.contains(&(autometrics::CALLER_HEADER, "makes_request")));
+
+    let remote_caller = autometrics::extract_caller(|name| {
+        headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    });
+    autometrics::with_remote_caller_fn(remote_caller, handles_request)().unwrap();
+
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    assert!(metrics.lines().any(|line| {
+        line.starts_with("function_calls_count")
+            && line.contains(r#"function="handles_request""#)
+            && line.contains(r#"caller="makes_request""#)
+            && line.contains(r#"caller_service="upstream""#)
+    }));
+    // Only the function called directly by the remote caller gets the caller_service label
+    assert!(metrics.lines().any(|line| {
+        line.starts_with("function_calls_count")
+            && line.contains(r#"function="in_handler""#)
+            && line.contains(r#"caller="handles_request""#)
+            && !line.contains(r#"caller_service="upstream""#)
+    }));
+}
+
+#[test]
+fn invalid_remote_callers_are_ignored() {
+    let extract = |function: &'static str, service: &'static str| {
+        autometrics::extract_caller(|name| {
+            if name == autometrics::CALLER_HEADER {
+                Some(function)
+            } else {
+                Some(service)
+            }
+        })
+    };
+
+    let remote_caller = extract("api::get_user", "users-api").unwrap();
+    assert_eq!(remote_caller.function(), "api::get_user");
+    assert_eq!(remote_caller.service(), Some("users-api"));
+
+    assert_eq!(extract("", "users-api"), None);
+    assert_eq!(extract("get_user\nfake_metric 1", "users-api"), None);
+    assert_eq!(extract("a".repeat(129).leak(), "users-api"), None);
+    // An invalid service is left out, but the caller is kept
+    let remote_caller = extract("get_user", "users api").unwrap();
+    assert_eq!(remote_caller.service(), None);
+}
+
+#[autometrics]
+fn makes_request() -> Vec<(&'static str, &'static str)> {
+    let mut headers = Vec::new();
+    autometrics::inject_caller(|name, value| headers.push((name, value)));
+    headers
+}
+
+#[autometrics]
+fn handles_request() -> Result<(), ()> {
+    in_handler()
+}
+
+#[autometrics]
+fn in_handler() -> Result<(), ()> {
+    Ok(())
+}
+
+#[autometrics]
+fn spawns_thread() {
+    std::thread::spawn(autometrics::with_caller_fn(in_thread))
+        .join()
+        .unwrap()
+        .unwrap();
+}
+
+#[autometrics]
+fn in_thread() -> Result<(), ()> {
+    Ok(())
+}
+
+#[autometrics]
+fn creates_future() -> std::pin::Pin<Box<dyn Future<Output = Result<(), ()>>>> {
+    Box::pin(autometrics::with_caller(in_future()))
+}
+
+#[autometrics]
+async fn in_future() -> Result<(), ()> {
+    Ok(())
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+fn has_caller(function: &str, caller: &str) -> bool {
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    let function = format!(r#"function="{function}""#);
+    let caller = format!(r#"caller="{caller}""#);
+    metrics.lines().any(|line| {
+        line.starts_with("function_calls_count")
+            && line.contains(&function)
+            && line.contains(&caller)
+    })
+}
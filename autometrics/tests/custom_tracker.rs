@@ -0,0 +1,50 @@
+use autometrics::{autometrics, FunctionCall, Label, Tracker};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Default)]
+struct RecordingTracker {
+    started: Arc<Mutex<Vec<&'static str>>>,
+    finished: Arc<Mutex<Vec<Vec<Label>>>>,
+}
+
+impl Tracker for RecordingTracker {
+    fn start(&self, call: &FunctionCall<'_>) {
+        self.started.lock().unwrap().push(call.function());
+    }
+
+    fn finish(&self, _call: &FunctionCall<'_>, counter_labels: &[Label], _duration: Duration) {
+        self.finished.lock().unwrap().push(counter_labels.to_vec());
+    }
+}
+
+#[test]
+fn global_tracker_records_calls() {
+    let tracker = RecordingTracker::default();
+    autometrics::set_global_tracker(Box::new(tracker.clone()));
+
+    outer();
+    assert_eq!(*tracker.started.lock().unwrap(), ["outer", "inner"]);
+
+    let finished = tracker.finished.lock().unwrap().clone();
+    assert_eq!(finished.len(), 2);
+    assert!(finished[0].contains(&("function", "inner")));
+    assert!(finished[0].contains(&("caller", "outer")));
+    assert!(finished[0].contains(&("result", "error")));
+    assert!(finished[1].contains(&("function", "outer")));
+
+    // Calls are no longer recorded by the custom tracker after it is reset
+    autometrics::reset_global_tracker();
+    outer();
+    assert_eq!(tracker.started.lock().unwrap().len(), 2);
+}
+
+#[autometrics]
+fn outer() {
+    let _ = inner();
+}
+
+#[autometrics]
+fn inner() -> Result<(), ()> {
+    Err(())
+}
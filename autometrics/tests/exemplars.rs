@@ -0,0 +1,115 @@
+#![cfg(all(
+    feature = "exemplars",
+    any(
+        feature = "native",
+        all(feature = "opentelemetry", feature = "prometheus-exporter")
+    )
+))]
+
+use autometrics::autometrics;
+use opentelemetry_api::trace::{
+    SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+};
+use opentelemetry_api::Context;
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const SPAN_ID: &str = "00f067aa0ba902b7";
+
+#[cfg(feature = "native")]
+#[test]
+fn records_exemplars() {
+    use autometrics::{encode_native_metrics, encode_native_metrics_protobuf, TextFormat};
+
+    call_functions();
+
+    let exemplar = format!(r#"# {{trace_id="{TRACE_ID}",span_id="{SPAN_ID}"}}"#);
+    let metrics = encode_native_metrics(TextFormat::OpenMetrics);
+    let traced_buckets: Vec<_> = metrics
+        .lines()
+        .filter(|line| {
+            line.starts_with("function_calls_duration_seconds_bucket{function=\"traced\"")
+        })
+        .collect();
+    assert!(traced_buckets.iter().any(|line| line.contains(&exemplar)));
+    // The counter's samples end in `_total` in OpenMetrics, which allows exemplars on counters
+    assert!(metrics.contains("# TYPE function_calls_count counter"));
+    assert!(metrics.lines().any(|line| {
+        line.starts_with("function_calls_count_total{function=\"traced\"")
+            && line.contains(&exemplar)
+    }));
+    assert!(!metrics
+        .lines()
+        .filter(|line| line.contains("function=\"untraced\""))
+        .any(|line| line.contains(" # {")));
+
+    // Exemplars are not part of the Prometheus text format
+    let metrics = encode_native_metrics(TextFormat::Prometheus);
+    assert!(!metrics.contains("trace_id"));
+
+    let metrics = encode_native_metrics_protobuf();
+    let contains = |bytes: &[u8]| metrics.windows(bytes.len()).any(|window| window == bytes);
+    assert!(contains(TRACE_ID.as_bytes()));
+    assert!(contains(SPAN_ID.as_bytes()));
+}
+
+#[cfg(all(
+    feature = "opentelemetry",
+    feature = "prometheus-exporter",
+    not(feature = "native")
+))]
+#[test]
+fn exports_opentelemetry_exemplars() {
+    use autometrics::{encode_global_metrics_as, Format};
+
+    let _exporter = autometrics::global_metrics_exporter();
+    call_functions();
+
+    let exemplar = format!(r#"# {{trace_id="{TRACE_ID}",span_id="{SPAN_ID}"}}"#);
+    let metrics =
+        String::from_utf8(encode_global_metrics_as(Format::OpenMetrics).unwrap()).unwrap();
+    let traced_buckets: Vec<_> = metrics
+        .lines()
+        .filter(|line| {
+            line.starts_with("function_calls_duration_seconds_bucket{")
+                && line.contains("function=\"traced\"")
+        })
+        .collect();
+    assert!(!traced_buckets.is_empty());
+    assert!(traced_buckets.iter().any(|line| line.contains(&exemplar)));
+    assert!(metrics.lines().any(|line| {
+        line.starts_with("function_calls_count_total{")
+            && line.contains("function=\"traced\"")
+            && line.contains(&exemplar)
+    }));
+    assert!(!metrics
+        .lines()
+        .filter(|line| line.contains("function=\"untraced\""))
+        .any(|line| line.contains(" # {")));
+
+    // Exemplars are not part of the Prometheus text format
+    let metrics = String::from_utf8(encode_global_metrics_as(Format::Text).unwrap()).unwrap();
+    assert!(metrics.contains("function=\"traced\""));
+    assert!(!metrics.contains("trace_id"));
+}
+
+/// Call one function without a trace, which does not get exemplars, and one with a sampled trace
+fn call_functions() {
+    untraced();
+
+    let span_context = SpanContext::new(
+        TraceId::from_hex(TRACE_ID).unwrap(),
+        SpanId::from_hex(SPAN_ID).unwrap(),
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    let context = Context::current().with_remote_span_context(span_context);
+    let _guard = context.attach();
+    traced();
+}
+
+#[autometrics]
+fn traced() {}
+
+#[autometrics]
+fn untraced() {}
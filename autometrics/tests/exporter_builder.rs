@@ -0,0 +1,28 @@
+#![cfg(feature = "prometheus-exporter")]
+
+use autometrics::{autometrics, ExporterInitializationError, PrometheusExporterBuilder};
+
+#[test]
+fn custom_exporter() {
+    PrometheusExporterBuilder::new()
+        .buckets([0.1, 0.3, 0.9])
+        .const_label("service", "test")
+        .init()
+        .unwrap();
+
+    assert!(matches!(
+        PrometheusExporterBuilder::new().init(),
+        Err(ExporterInitializationError::AlreadyInitialized)
+    ));
+
+    add(1, 2);
+
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    assert!(metrics.contains(r#"service="test""#));
+    assert!(metrics.contains(r#"le="0.3""#));
+}
+
+#[autometrics]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
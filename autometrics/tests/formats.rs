@@ -0,0 +1,58 @@
+#![cfg(feature = "prometheus-exporter")]
+
+use autometrics::{autometrics, encode_global_metrics_as, Format};
+
+#[test]
+fn picks_format_from_accept_header() {
+    let prometheus = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+    assert_eq!(
+        Format::from_accept_header(prometheus),
+        Some(Format::OpenMetrics)
+    );
+
+    let native_histograms = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.2";
+    assert_eq!(
+        Format::from_accept_header(native_histograms),
+        Some(Format::Protobuf)
+    );
+
+    assert_eq!(Format::from_accept_header("*/*"), Some(Format::Text));
+    assert_eq!(
+        Format::from_accept_header("application/openmetrics-text;q=0, text/plain"),
+        Some(Format::Text)
+    );
+    assert_eq!(Format::from_accept_header("application/json"), None);
+}
+
+#[test]
+fn encodes_formats() {
+    let _exporter = autometrics::global_metrics_exporter();
+    add(1, 2);
+
+    let text = String::from_utf8(encode_global_metrics_as(Format::Text).unwrap()).unwrap();
+    assert!(text.contains(r#"function="add""#));
+    assert!(!text.contains("# EOF"));
+
+    let openmetrics =
+        String::from_utf8(encode_global_metrics_as(Format::OpenMetrics).unwrap()).unwrap();
+    assert!(openmetrics.contains(r#"function="add""#));
+    assert!(openmetrics.contains("# TYPE function_calls_duration_seconds histogram"));
+    assert!(openmetrics.contains("# UNIT function_calls_duration_seconds seconds"));
+    // Only the native metrics know when their time series were created
+    assert_eq!(
+        openmetrics.contains("function_calls_count_created{"),
+        cfg!(feature = "native")
+    );
+    assert!(openmetrics.ends_with("# EOF\n"));
+    assert_eq!(openmetrics.matches("# EOF").count(), 1);
+
+    let protobuf = encode_global_metrics_as(Format::Protobuf).unwrap();
+    let contains = |bytes: &[u8]| protobuf.windows(bytes.len()).any(|window| window == bytes);
+    assert!(contains(b"function_calls_duration"));
+    assert!(contains(b"add"));
+}
+
+#[autometrics]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
@@ -10,10 +10,83 @@ fn main() {
 
     add(1, 2);
     other_function().unwrap();
+    function_with_labels().unwrap();
+    function_with_dynamic_labels("acme".to_string()).unwrap();
+    function_with_repeated_labels("eu-west".to_string(), "gold").unwrap();
+    Db::new().get_user(1).unwrap();
+    Db::new().foo().unwrap();
+    cache_lookup();
+    batch_job().unwrap();
 
     let result = autometrics::encode_global_metrics().unwrap();
 
     assert_ne!(result, "");
+    assert!(result.contains(r#"team="payments""#));
+    assert!(result.contains(r#"tenant="acme""#));
+    assert!(result.contains(r#"owner="billing""#));
+    assert!(result.contains(r#"region="eu-west""#));
+    assert!(result.contains(r#"plan="gold""#));
+    assert!(result.contains(r#"function="Db::get_user""#));
+    assert!(result.contains(r#"function="<Db as Foo>::foo""#));
+    assert!(result.contains(r#"le="0.0001""#));
+    assert!(result.contains(r#"le="0.75""#));
+}
+
+#[cfg(feature = "prometheus-exporter")]
+#[test]
+fn panics_are_recorded() {
+    let _ = autometrics::global_metrics_exporter();
+
+    let result = std::panic::catch_unwind(panicking_function);
+    assert!(result.is_err());
+
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    assert!(metrics
+        .lines()
+        .any(|line| line.starts_with("function_calls_count")
+            && line.contains(r#"function="panicking_function""#)
+            && line.contains(r#"result="panic""#)));
+}
+
+#[autometrics(track_concurrency)]
+fn panicking_function() -> Result<(), ()> {
+    panic!("this function always panics")
+}
+
+#[cfg(feature = "prometheus-exporter")]
+#[test]
+fn cancelled_calls_are_recorded() {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Wake, Waker};
+
+    struct NoopWaker;
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let _ = autometrics::global_metrics_exporter();
+
+    // Poll the future once so the call starts, then drop it before it completes
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut future = Box::pin(never_completes());
+    assert!(future
+        .as_mut()
+        .poll(&mut Context::from_waker(&waker))
+        .is_pending());
+    drop(future);
+
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    assert!(metrics
+        .lines()
+        .any(|line| line.starts_with("function_calls_count")
+            && line.contains(r#"function="never_completes""#)
+            && line.contains(r#"result="cancelled""#)));
+}
+
+#[autometrics(track_concurrency)]
+async fn never_completes() {
+    std::future::pending::<()>().await
 }
 
 #[autometrics]
@@ -36,11 +109,43 @@ pub async fn get_index_handler() -> Result<String, ()> {
     Ok("Hello world!".to_string())
 }
 
+#[autometrics(labels(team = "payments", tier = "critical"))]
+fn function_with_labels() -> Result<(), ()> {
+    Ok(())
+}
+
+#[autometrics(label(tenant = tenant.as_str()))]
+fn function_with_dynamic_labels(tenant: String) -> Result<String, ()> {
+    Ok(tenant)
+}
+
+#[autometrics(
+    labels(team = "payments"),
+    labels(owner = "billing"),
+    label(region = region.as_str()),
+    dynamic_labels(plan = plan)
+)]
+fn function_with_repeated_labels(region: String, plan: &str) -> Result<(), ()> {
+    let _ = (region, plan);
+    Ok(())
+}
+
+#[autometrics(buckets = "micro")]
+fn cache_lookup() -> Option<String> {
+    None
+}
+
+#[autometrics(buckets = [0.25, 0.75, 2.5])]
+fn batch_job() -> Result<(), ()> {
+    Ok(())
+}
+
 #[autometrics(track_concurrency)]
 fn other_function() -> Result<String, ()> {
     Ok("Hello world!".to_string())
 }
 
+#[derive(Default)]
 pub struct Db {}
 
 #[autometrics]
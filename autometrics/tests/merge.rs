@@ -0,0 +1,46 @@
+#![cfg(all(feature = "prometheus-exporter", feature = "metrics"))]
+
+use prometheus::{default_registry, IntCounter, IntCounterVec, Opts};
+
+#[test]
+fn merges_registry_and_recorder() {
+    let _exporter = autometrics::global_metrics_exporter();
+
+    let counter =
+        IntCounterVec::new(Opts::new("merged_total", "Merged counter"), &["source"]).unwrap();
+    default_registry()
+        .register(Box::new(counter.clone()))
+        .unwrap();
+    counter.with_label_values(&["registry"]).inc();
+    metrics::increment_counter!("merged_total", "source" => "recorder");
+
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    assert_eq!(metrics.matches("# TYPE merged_total counter").count(), 1);
+    let registry = metrics
+        .find(r#"merged_total{source="registry"} 1"#)
+        .unwrap();
+    let recorder = metrics
+        .find(r#"merged_total{source="recorder"} 1"#)
+        .unwrap();
+    // The time series are sorted by their labels
+    assert!(recorder < registry);
+
+    let openmetrics = autometrics::encode_global_metrics_as(autometrics::Format::OpenMetrics)
+        .map(String::from_utf8)
+        .unwrap()
+        .unwrap();
+    assert_eq!(openmetrics.matches("# TYPE merged counter").count(), 1);
+
+    // The same family cannot have different types
+    let conflicting = IntCounter::new("conflicting", "Conflicting metric").unwrap();
+    default_registry()
+        .register(Box::new(conflicting.clone()))
+        .unwrap();
+    conflicting.inc();
+    metrics::gauge!("conflicting", 1.0);
+
+    let err = autometrics::encode_global_metrics().unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("`conflicting` is exported as both a counter and a gauge"));
+}
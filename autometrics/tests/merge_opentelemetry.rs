@@ -0,0 +1,33 @@
+#![cfg(feature = "prometheus-exporter")]
+
+use opentelemetry_api::{global, Context, KeyValue};
+use prometheus::{default_registry, IntCounterVec, Opts};
+
+#[test]
+fn merges_registry_and_opentelemetry() {
+    let _exporter = autometrics::global_metrics_exporter();
+
+    let counter =
+        IntCounterVec::new(Opts::new("migrated", "Migrated counter"), &["source"]).unwrap();
+    default_registry()
+        .register(Box::new(counter.clone()))
+        .unwrap();
+    counter.with_label_values(&["prometheus"]).inc();
+    global::meter("merge-test")
+        .u64_counter("migrated")
+        .init()
+        .add(
+            &Context::current(),
+            1,
+            &[KeyValue::new("source", "opentelemetry")],
+        );
+
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    assert_eq!(metrics.matches("# TYPE migrated counter").count(), 1);
+    for source in ["prometheus", "opentelemetry"] {
+        let label = format!(r#"source="{source}""#);
+        assert!(metrics
+            .lines()
+            .any(|line| line.starts_with("migrated{") && line.contains(&label)));
+    }
+}
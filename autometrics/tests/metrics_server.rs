@@ -0,0 +1,94 @@
+#![cfg(feature = "metrics-server")]
+
+use autometrics::autometrics;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn serves_metrics() {
+    let _exporter = autometrics::global_metrics_exporter();
+    let server = autometrics::serve_metrics("127.0.0.1:0").unwrap();
+    let addr = server.local_addr();
+
+    add(1, 2);
+
+    // A client that connects without sending a request does not hold up the other scrapes
+    let _idle = TcpStream::connect(addr).unwrap();
+    let start = Instant::now();
+    let response = get(addr, "/metrics", None);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(response.contains(r#"function="add""#));
+
+    let response = get(addr, "/metrics", Some("text/plain;q=0.5, */*;q=0.1"));
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    // The Accept header sent by Prometheus
+    let response = get(
+        addr,
+        "/metrics",
+        Some("application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"),
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Type: application/openmetrics-text; version=1.0.0"));
+    assert!(response.contains(r#"function="add""#));
+    assert!(response.ends_with("# EOF\n"));
+
+    let response = get(addr, "/metrics", Some("application/json"));
+    assert!(response.starts_with("HTTP/1.1 406 Not Acceptable"));
+
+    let response = get(addr, "/other", None);
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+    // A client that keeps sending the request head slowly is disconnected after the deadline
+    let mut slow = TcpStream::connect(addr).unwrap();
+    slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let start = Instant::now();
+    let mut disconnected = false;
+    for _ in 0..20 {
+        if slow.write_all(b"G").is_err() {
+            disconnected = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    if !disconnected {
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.is_empty());
+    }
+    assert!(start.elapsed() < Duration::from_secs(3));
+
+    // More clients than there are workers are served as well
+    let idle: Vec<_> = (0..6).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    let response = get(addr, "/metrics", None);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    drop(idle);
+
+    server.shutdown();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+fn get(addr: SocketAddr, path: &str, accept: Option<&str>) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let accept = accept
+        .map(|accept| format!("Accept: {accept}\r\n"))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\n{accept}\r\n"
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[autometrics]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
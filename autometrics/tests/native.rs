@@ -0,0 +1,87 @@
+#![cfg(feature = "native")]
+
+use autometrics::{autometrics, encode_native_metrics, encode_native_metrics_protobuf, TextFormat};
+
+#[test]
+fn records_native_metrics() {
+    for _ in 0..3 {
+        let _ = divide(4, 2);
+    }
+    let _ = divide(1, 0);
+
+    let metrics = encode_native_metrics(TextFormat::Prometheus);
+    assert!(metrics.contains("# TYPE function_calls_count counter"));
+    assert!(metrics
+        .contains(r#"function_calls_count{function="divide",module="native",result="ok"} 3"#));
+    assert!(metrics
+        .contains(r#"function_calls_count{function="divide",module="native",result="error"} 1"#));
+    assert!(metrics.contains(
+        r#"function_calls_duration_bucket{function="divide",module="native",le="+Inf"} 4"#
+    ));
+    assert!(
+        metrics.contains(r#"function_calls_duration_count{function="divide",module="native"} 4"#)
+    );
+    assert!(!metrics.contains("# EOF"));
+
+    let metrics = encode_native_metrics(TextFormat::OpenMetrics);
+    assert!(metrics.contains("# UNIT function_calls_duration_seconds seconds"));
+    assert!(metrics.contains(
+        r#"function_calls_duration_seconds_created{function="divide",module="native"} "#
+    ));
+    assert!(metrics.contains(
+        r#"function_calls_count_created{function="divide",module="native",result="ok"} "#
+    ));
+    assert!(metrics.ends_with("# EOF\n"));
+}
+
+#[test]
+fn tracks_concurrency() {
+    concurrent();
+
+    let metrics = encode_native_metrics(TextFormat::Prometheus);
+    assert!(
+        metrics.contains(r#"function_calls_concurrent{function="concurrent",module="native"} 0"#)
+    );
+}
+
+#[test]
+fn encodes_protobuf() {
+    let _ = multiply(6, 3);
+
+    let metrics = encode_native_metrics_protobuf();
+    let contains = |bytes: &[u8]| metrics.windows(bytes.len()).any(|window| window == bytes);
+    assert!(contains(b"function_calls_count"));
+    assert!(contains(b"function_calls_duration"));
+    assert!(contains(b"multiply"));
+}
+
+#[cfg(feature = "native-histograms")]
+#[test]
+fn encodes_native_histograms() {
+    native_histogram();
+
+    let metrics = encode_native_metrics_protobuf();
+    let contains = |bytes: &[u8]| metrics.windows(bytes.len()).any(|window| window == bytes);
+    // The schema of the exponential buckets (field 5, zigzag encoded) and the zero threshold (field 6)
+    assert!(contains(&[0x28, 6]));
+    assert!(contains(
+        &[[0x31].as_slice(), &2f64.powi(-30).to_le_bytes()].concat()
+    ));
+}
+
+#[autometrics]
+fn divide(a: i32, b: i32) -> Result<i32, ()> {
+    a.checked_div(b).ok_or(())
+}
+
+#[autometrics]
+fn multiply(a: i32, b: i32) -> i32 {
+    a * b
+}
+
+#[autometrics(track_concurrency)]
+fn concurrent() {}
+
+#[cfg(feature = "native-histograms")]
+#[autometrics]
+fn native_histogram() {}
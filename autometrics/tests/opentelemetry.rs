@@ -0,0 +1,42 @@
+#![cfg(all(feature = "opentelemetry", feature = "prometheus-exporter"))]
+
+use autometrics::autometrics;
+use opentelemetry_sdk::{
+    export::metrics::aggregation,
+    metrics::{controllers, processors, selectors},
+};
+use prometheus::Registry;
+
+#[test]
+fn records_with_meter_provider_installed_after_first_call() {
+    // The instruments are created with the default no-op meter provider
+    add(1, 2);
+
+    let controller = controllers::basic(processors::factory(
+        selectors::simple::inexpensive(),
+        aggregation::cumulative_temporality_selector(),
+    ))
+    .build();
+    let exporter = opentelemetry_prometheus::exporter(controller)
+        .with_registry(Registry::new())
+        .init();
+    autometrics::set_meter_provider(exporter.meter_provider().unwrap());
+
+    // And created again with the new meter provider
+    add(3, 4);
+
+    let families = exporter.registry().gather();
+    let counter = families
+        .iter()
+        .find(|family| family.get_name() == "function_calls_count")
+        .expect("the function call was not recorded with the new meter provider");
+    assert!(counter.get_metric().iter().any(|metric| metric
+        .get_label()
+        .iter()
+        .any(|label| label.get_name() == "function" && label.get_value() == "add")));
+}
+
+#[autometrics]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
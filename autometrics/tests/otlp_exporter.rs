@@ -0,0 +1,74 @@
+#![cfg(feature = "otlp-exporter")]
+
+use autometrics::{autometrics, OtlpExporterBuilder, OtlpExporterError, Temporality};
+use common::{stub_server, Request};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn pushes_metrics() {
+    assert!(matches!(
+        OtlpExporterBuilder::new("localhost:4318").init(),
+        Err(OtlpExporterError::InvalidEndpoint(_))
+    ));
+    #[cfg(not(feature = "tls"))]
+    match OtlpExporterBuilder::new("https://localhost:4318").init() {
+        Err(OtlpExporterError::InvalidEndpoint(err)) => assert!(err.contains("`tls` feature")),
+        _ => panic!("https:// endpoints are only supported with the tls feature"),
+    }
+
+    let (addr, requests) = stub_server(Vec::new());
+    let exporter = OtlpExporterBuilder::new(format!("http://{addr}"))
+        .interval(Duration::from_millis(100))
+        .temporality(Temporality::Delta)
+        .resource_attribute("service.name", "otlp-test")
+        .init()
+        .unwrap();
+
+    assert!(matches!(
+        autometrics::init_otlp(format!("http://{addr}"), Duration::from_secs(1)),
+        Err(OtlpExporterError::AlreadyInitialized)
+    ));
+
+    add(1, 2);
+
+    // The metrics are pushed periodically
+    let request = receive_metrics(&requests);
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/v1/metrics");
+    assert_eq!(request.content_type, "application/x-protobuf");
+    assert!(request.body_contains(b"function.calls.duration"));
+    assert!(request.body_contains(b"otlp-test"));
+    assert!(request.body_contains(b"add"));
+
+    // The last metrics are pushed on shutdown
+    add(3, 4);
+    exporter.shutdown().unwrap();
+    receive_metrics(&requests);
+
+    // And then no more metrics are pushed
+    thread::sleep(Duration::from_millis(300));
+    requests.try_iter().for_each(drop);
+    thread::sleep(Duration::from_millis(300));
+    assert!(requests.try_recv().is_err());
+}
+
+#[autometrics]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+/// Wait for a request that includes the function call counter
+fn receive_metrics(requests: &Receiver<Request>) -> Request {
+    loop {
+        let request = requests
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the metrics were not pushed");
+        if request.body_contains(b"function.calls.count") {
+            return request;
+        }
+    }
+}
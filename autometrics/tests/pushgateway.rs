@@ -0,0 +1,130 @@
+#![cfg(feature = "pushgateway")]
+
+use autometrics::{autometrics, PushgatewayBuilder, PushgatewayError};
+use common::{stub_server, Request};
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn pushes_metrics() {
+    let _exporter = autometrics::global_metrics_exporter();
+    add(1, 2);
+
+    // The first attempt fails with a server error and is retried
+    let (addr, requests) = stub_server(vec![503]);
+    let pushgateway = PushgatewayBuilder::new(format!("http://{addr}"), "nightly")
+        .grouping_key("instance", "worker-1")
+        .backoff(Duration::from_millis(10))
+        .build()
+        .unwrap();
+    pushgateway.push().unwrap();
+
+    let requests: Vec<Request> = requests.try_iter().collect();
+    assert_eq!(requests.len(), 2);
+    for request in &requests {
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/metrics/job/nightly/instance/worker-1");
+        assert!(request.content_type.starts_with("text/plain"));
+        assert!(request.body_contains(br#"function="add""#));
+    }
+}
+
+#[test]
+fn does_not_retry_client_errors() {
+    let (addr, requests) = stub_server(vec![400, 400]);
+    let pushgateway = PushgatewayBuilder::new(format!("http://{addr}"), "nightly")
+        .backoff(Duration::from_millis(10))
+        .build()
+        .unwrap();
+    assert!(matches!(
+        pushgateway.flush(),
+        Err(PushgatewayError::UnexpectedStatus(400))
+    ));
+    assert_eq!(requests.try_iter().count(), 1);
+
+    // Flushed clients do not push again when dropped
+    drop(pushgateway);
+    assert_eq!(requests.try_iter().count(), 0);
+}
+
+#[test]
+fn pushes_when_dropped() {
+    let (addr, requests) = stub_server(Vec::new());
+    let pushgateway = PushgatewayBuilder::new(format!("http://{addr}/prefix/"), "nightly")
+        .grouping_key("path", "/var/data")
+        .grouping_key("empty", "")
+        .build()
+        .unwrap();
+    drop(pushgateway);
+
+    let request = requests.try_recv().unwrap();
+    assert_eq!(
+        request.path,
+        "/prefix/metrics/job/nightly/path@base64/L3Zhci9kYXRh/empty@base64/="
+    );
+}
+
+#[test]
+fn does_not_push_when_dropped_while_panicking() {
+    let (addr, requests) = stub_server(Vec::new());
+    let pushgateway = PushgatewayBuilder::new(format!("http://{addr}"), "nightly")
+        .build()
+        .unwrap();
+    let result = std::thread::spawn(move || {
+        let _pushgateway = pushgateway;
+        panic!("the job failed");
+    })
+    .join();
+    assert!(result.is_err());
+    assert_eq!(requests.try_iter().count(), 0);
+}
+
+#[test]
+fn invalid_url() {
+    assert!(matches!(
+        PushgatewayBuilder::new("localhost:9091", "nightly").build(),
+        Err(PushgatewayError::InvalidUrl(_))
+    ));
+    #[cfg(not(feature = "tls"))]
+    match PushgatewayBuilder::new("https://localhost:9091", "nightly").build() {
+        Err(PushgatewayError::InvalidUrl(err)) => assert!(err.contains("`tls` feature")),
+        _ => panic!("https:// URLs are only supported with the tls feature"),
+    }
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn pushes_over_tls() {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let _exporter = autometrics::global_metrics_exporter();
+
+    // The server does not speak TLS, so it only checks that the client starts a TLS handshake
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut record_type = [0];
+            let _ = stream.unwrap().read_exact(&mut record_type);
+            let _ = sender.send(record_type[0]);
+        }
+    });
+
+    let pushgateway = PushgatewayBuilder::new(format!("https://{addr}"), "nightly")
+        .retries(0)
+        .build()
+        .unwrap();
+    assert!(matches!(pushgateway.push(), Err(PushgatewayError::Io(_))));
+    // 22 is the type of the TLS handshake record
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(22));
+}
+
+#[autometrics]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
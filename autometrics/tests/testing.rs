@@ -0,0 +1,75 @@
+#![cfg(feature = "testing")]
+
+use autometrics::{assert_called, autometrics, testing::TestRecorder};
+
+#[test]
+fn records_calls_for_the_current_test() {
+    let recorder = TestRecorder::install();
+
+    add(1, 2);
+    let _ = checked_add(1, 2);
+    let _ = checked_add(i32::MAX, 1);
+
+    assert_called!(add, times = 1);
+    assert_called!(checked_add, times = 1, result = "ok");
+    assert_called!(checked_add, times = 1, result = "error");
+    assert_called!(add, caller = "", module = "testing");
+
+    let calls = recorder.calls_to("checked_add");
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].result(), Some("ok"));
+    assert!(calls[0].labels().contains(&("function", "checked_add")));
+
+    recorder.clear();
+    assert!(recorder.calls().is_empty());
+}
+
+#[test]
+fn records_the_caller() {
+    let _recorder = TestRecorder::install();
+
+    outer();
+
+    assert_called!(checked_add, times = 1, caller = "outer");
+    assert_called!(outer, times = 1);
+}
+
+#[test]
+fn nested_recorders() {
+    let outer_recorder = TestRecorder::install();
+    add(1, 2);
+
+    {
+        let inner_recorder = TestRecorder::install();
+        add(3, 4);
+        assert_eq!(inner_recorder.calls().len(), 1);
+    }
+
+    add(5, 6);
+    assert_eq!(outer_recorder.calls().len(), 2);
+}
+
+#[test]
+#[should_panic(expected = "expected 2 matching call(s) to `add` but found 1")]
+fn assert_called_fails_on_mismatch() {
+    let _recorder = TestRecorder::install();
+
+    add(1, 2);
+
+    assert_called!(add, times = 2);
+}
+
+#[autometrics]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[autometrics]
+fn checked_add(a: i32, b: i32) -> Result<i32, ()> {
+    a.checked_add(b).ok_or(())
+}
+
+#[autometrics]
+fn outer() {
+    let _ = checked_add(1, 2);
+}
@@ -0,0 +1,99 @@
+#![cfg(all(feature = "tower", feature = "prometheus-exporter"))]
+
+use autometrics::{autometrics, tower::AutometricsLayer};
+use http::{Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::future::{ready, Future, Ready};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use tower_layer::Layer;
+use tower_service::Service;
+
+#[test]
+fn layer_records_requests() {
+    let _ = autometrics::global_metrics_exporter();
+
+    let mut service = AutometricsLayer::new(|request: &Request<()>| match request.uri().path() {
+        "/users" => "/users",
+        _ => "/broken",
+    })
+    .layer(Handler);
+    assert_eq!(call(&mut service, "/users").status(), StatusCode::OK);
+    assert_eq!(
+        call(&mut service, "/broken").status(),
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+
+    let mut service = AutometricsLayer::new(|_: &Request<()>| "/items/:id")
+        .module("api")
+        .error_if(|response: &Response<()>| response.status().is_client_error())
+        .layer(Handler);
+    call(&mut service, "/items/404");
+
+    let metrics = autometrics::encode_global_metrics().unwrap();
+    let has_count = |labels: &[&str]| {
+        metrics.lines().any(|line| {
+            line.starts_with("function_calls_count")
+                && labels.iter().all(|label| line.contains(label))
+        })
+    };
+    assert!(has_count(&[
+        r#"function="/users""#,
+        r#"module="http""#,
+        r#"result="ok""#
+    ]));
+    assert!(has_count(&[r#"function="/broken""#, r#"result="error""#]));
+    assert!(has_count(&[
+        r#"function="/items/:id""#,
+        r#"module="api""#,
+        r#"result="error""#
+    ]));
+    // Instrumented functions called by the handler report the route as their caller
+    assert!(has_count(&[r#"function="lookup""#, r#"caller="/users""#]));
+}
+
+fn call<S>(service: &mut S, path: &str) -> Response<()>
+where
+    S: Service<Request<()>, Response = Response<()>, Error = Infallible>,
+{
+    struct NoopWaker;
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    let request = Request::builder().uri(path).body(()).unwrap();
+    let mut future = Box::pin(service.call(request));
+    match future.as_mut().poll(&mut cx) {
+        Poll::Ready(Ok(response)) => response,
+        _ => panic!("the handler should complete immediately"),
+    }
+}
+
+#[derive(Clone)]
+struct Handler;
+
+impl Service<Request<()>> for Handler {
+    type Response = Response<()>;
+    type Error = Infallible;
+    type Future = Ready<Result<Response<()>, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<()>) -> Self::Future {
+        let status = lookup(request.uri().path());
+        ready(Ok(Response::builder().status(status).body(()).unwrap()))
+    }
+}
+
+#[autometrics]
+fn lookup(path: &str) -> StatusCode {
+    match path {
+        "/users" => StatusCode::OK,
+        "/items/404" => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,53 @@
+#![cfg(feature = "tracing-subscriber")]
+
+use autometrics::{tracing_subscriber::AutometricsLayer, FunctionCall, Label, Tracker};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing_subscriber::prelude::*;
+
+#[derive(Clone, Default)]
+struct RecordingTracker {
+    finished: Arc<Mutex<Vec<Vec<Label>>>>,
+}
+
+impl Tracker for RecordingTracker {
+    fn finish(&self, _call: &FunctionCall<'_>, counter_labels: &[Label], _duration: Duration) {
+        self.finished.lock().unwrap().push(counter_labels.to_vec());
+    }
+}
+
+#[test]
+fn records_spans() {
+    let tracker = RecordingTracker::default();
+    autometrics::set_global_tracker(Box::new(tracker.clone()));
+
+    let subscriber = tracing_subscriber::registry().with(AutometricsLayer::new());
+    tracing::subscriber::with_default(subscriber, || {
+        handle_request();
+
+        // Spans that are never entered are not recorded
+        let _ = tracing::info_span!("unused");
+    });
+
+    let finished = tracker.finished.lock().unwrap().clone();
+    assert_eq!(finished.len(), 2);
+
+    assert!(finished[0].contains(&("function", "load_user")));
+    assert!(finished[0].contains(&("module", "tracing_layer")));
+    assert!(finished[0].contains(&("caller", "handle_request")));
+    assert!(finished[0].contains(&("result", "error")));
+
+    assert!(finished[1].contains(&("function", "handle_request")));
+    assert!(finished[1].contains(&("caller", "")));
+    assert!(finished[1].contains(&("result", "ok")));
+}
+
+fn handle_request() {
+    let _span = tracing::info_span!("handle_request").entered();
+    load_user();
+}
+
+fn load_user() {
+    let span = tracing::info_span!("load_user", error = tracing::field::Empty).entered();
+    span.record("error", "user not found");
+}
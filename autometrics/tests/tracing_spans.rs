@@ -0,0 +1,118 @@
+#![cfg(feature = "tracing")]
+
+use autometrics::autometrics;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+type Fields = HashMap<String, String>;
+
+/// The name and fields of every span, in the order in which they were created
+#[derive(Clone, Default)]
+struct RecordingSubscriber {
+    spans: Arc<Mutex<Vec<(&'static str, Fields)>>>,
+}
+
+impl RecordingSubscriber {
+    fn span(&self, name: &str) -> Fields {
+        self.spans
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(span_name, _)| *span_name == name)
+            .map(|(_, fields)| fields.clone())
+            .unwrap_or_else(|| panic!("no span named {name}"))
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut Fields);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl Subscriber for RecordingSubscriber {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = HashMap::new();
+        span.record(&mut FieldVisitor(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((span.metadata().name(), fields));
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+        values.record(&mut FieldVisitor(fields));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[test]
+fn creates_spans() {
+    let subscriber = RecordingSubscriber::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        let _ = outer();
+    });
+
+    let outer = subscriber.span("outer");
+    assert_eq!(outer["function"], "outer");
+    assert_eq!(outer["module"], "tracing_spans");
+    assert_eq!(outer["caller"], "");
+    assert_eq!(outer["result"], "error");
+    assert_eq!(outer["error"], "something went wrong");
+
+    let inner = subscriber.span("inner");
+    assert_eq!(inner["caller"], "outer");
+    assert_eq!(inner["result"], "ok");
+    assert!(!inner.contains_key("error"));
+}
+
+#[test]
+fn skips_errors_that_do_not_implement_display() {
+    let subscriber = RecordingSubscriber::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        let _ = opaque_error();
+    });
+
+    let span = subscriber.span("opaque_error");
+    assert_eq!(span["result"], "error");
+    assert!(!span.contains_key("error"));
+}
+
+#[autometrics]
+fn outer() -> Result<(), String> {
+    inner()?;
+    Err("something went wrong".to_string())
+}
+
+#[autometrics]
+fn inner() -> Result<(), String> {
+    Ok(())
+}
+
+#[autometrics]
+fn opaque_error() -> Result<(), ()> {
+    Err(())
+}